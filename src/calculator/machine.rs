@@ -0,0 +1,217 @@
+use super::{Action, ChemState, Step};
+use crate::chemicals::FixedPoint;
+
+/// A replay of actions on the machine, following what every reservoir holds. Volumes assume
+/// reactions keep the volume of what was mixed, and a mix is named after what went into it.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Machine {
+    pub reservoirs:Vec<Slot>,
+    /// volume pressed into pills or bottled as the product
+    pub packaged:u32,
+    /// volume thrown away by `EjectDownTo` and `DumpByproduct`
+    pub ejected:u32,
+    /// volume pressed into side pills by `Eject`
+    pub emptied:u32,
+    /// the fixed point volumes are counted in
+    pub units:FixedPoint
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Slot {
+    pub volume:u32,
+    /// beaker size, 0 when the reservoir needs none
+    pub capacity:u32,
+    /// names of everything mixed in, in the order they arrived
+    pub contents:Vec<String>,
+    /// temperature it was last heated to, if it has been since it was filled
+    pub temp:Option<u32>
+}
+
+impl Slot {
+    pub fn contents_text(&self) -> String {
+        if self.contents.is_empty() {
+            return "empty".to_string();
+        }
+        return self.contents.join("+");
+    }
+
+    /// the contents for a sentence, ie "the hydrogen, carbon and sugar mix"
+    pub fn english(&self) -> String {
+        match self.contents.len() {
+            0 => "nothing".to_string(),
+            1 => self.contents[0].clone(),
+            n => format!("the {} and {} mix", self.contents[..n-1].join(", "), self.contents[n-1])
+        }
+    }
+
+    fn empty_out(&mut self) {
+        self.volume = 0;
+        self.contents.clear();
+        self.temp = None;
+    }
+}
+
+impl Machine {
+    /// the machine loaded as `state`, with the beakers in `sizes`
+    pub fn new(state:&ChemState, sizes:&[u32]) -> Machine {
+        let mut reservoirs = vec![];
+        for (i, reservoir) in state.chems.iter().enumerate() {
+            let mut slot = Slot {capacity:*sizes.get(i).unwrap_or(&0), ..Default::default()};
+            if let Some(contents) = &reservoir.contents {
+                slot.volume = contents.size();
+                slot.contents.push(contents.short_text());
+            }
+            reservoirs.push(slot);
+        }
+        return Machine {reservoirs, units:state.profile.units, ..Default::default()};
+    }
+
+    fn slot(&mut self, reservoir:u32) -> &mut Slot {
+        return &mut self.reservoirs[reservoir as usize - 1];
+    }
+
+    /// `action` in words, naming what it moves as the machine is before it runs
+    pub fn describe(&self, action:&Action) -> String {
+        let contents = |reservoir:u32| self.reservoirs[reservoir as usize - 1].contents_text();
+        let units = |amount:u32| self.units.text(amount);
+        match action.step {
+            Step::Transfer{amount, source, target} => format!("transfer {}u {} r{}->r{}", units(amount), contents(source), source, target),
+            Step::Heat{temp, target} => format!("heat r{} to {}K", target, temp),
+            Step::Eject{target} => format!("empty r{} into a side pill", target),
+            Step::DumpByproduct{target, remaining} => format!("dump r{} down to {}u", target, units(remaining)),
+            Step::EjectDownTo{target, amount} => format!("eject r{} down to {}u", target, units(amount)),
+            Step::CreateBottle{target, amount} => format!("bottle {}u of {} from r{}", units(amount), contents(target), target),
+            Step::CreatePill{target, amount} => format!("press a {}u pill of {} from r{}", units(amount), contents(target), target)
+        }
+    }
+
+    /// `action` as a step for someone making the recipe by hand at a dispenser, or `None` when
+    /// it would not change anything
+    pub fn instruct(&self, action:&Action) -> Option<String> {
+        let slot = |reservoir:u32| &self.reservoirs[reservoir as usize - 1];
+        let units = |amount:u32| self.units.text(amount);
+        let step = match action.step {
+            Step::Transfer{amount, source, target} => format!("Pour {}u of {} from beaker {} into beaker {}", units(amount.min(slot(source).volume)), slot(source).english(), source, target),
+            Step::Heat{temp, target} => format!("Heat beaker {} to {}K", target, temp),
+            Step::Eject{target} => format!("Make a pill of everything in beaker {} and set it aside", target),
+            Step::DumpByproduct{target, remaining:amount} | Step::EjectDownTo{target, amount} => {
+                let removed = slot(target).volume.saturating_sub(amount);
+                if removed == 0 {
+                    return None;
+                }
+                format!("Pour {}u out of beaker {} and throw it away, leaving {}u", units(removed), target, units(amount))
+            },
+            Step::CreateBottle{target, amount} => format!("Fill a {}u vial from beaker {}", units(amount.min(slot(target).volume)), target),
+            Step::CreatePill{target, amount} => format!("Make a {}u pill from beaker {}", units(amount.min(slot(target).volume)), target)
+        };
+        return Some(step);
+    }
+
+    /// run `action`, returning the (1-indexed) reservoirs whose volume changed
+    pub fn apply(&mut self, action:&Action) -> Vec<u32> {
+        match action.step {
+            Step::Transfer{amount, source, target} => {
+                let moved = amount.min(self.slot(source).volume);
+                let source_slot = self.slot(source).clone();
+                self.slot(source).volume -= moved;
+                if self.slot(source).volume == 0 {
+                    self.slot(source).empty_out();
+                }
+                let target_slot = self.slot(target);
+                if target_slot.volume == 0 {
+                    target_slot.contents.clear();
+                    target_slot.temp = None;
+                }
+                target_slot.volume += moved;
+                for name in source_slot.contents {
+                    if !target_slot.contents.contains(&name) {
+                        target_slot.contents.push(name);
+                    }
+                }
+                return changed(moved, vec![source, target]);
+            },
+            Step::Heat{temp, target} => {
+                self.slot(target).temp = Some(temp);
+                return vec![];
+            },
+            Step::Eject{target} => {
+                let volume = self.slot(target).volume;
+                self.emptied += volume;
+                self.slot(target).empty_out();
+                return changed(volume, vec![target]);
+            },
+            Step::EjectDownTo{target, amount} | Step::DumpByproduct{target, remaining:amount} => {
+                let removed = self.slot(target).volume.saturating_sub(amount);
+                self.ejected += removed;
+                self.slot(target).volume -= removed;
+                if self.slot(target).volume == 0 {
+                    self.slot(target).empty_out();
+                }
+                return changed(removed, vec![target]);
+            },
+            Step::CreateBottle{target, amount} | Step::CreatePill{target, amount} => {
+                let packaged = amount.min(self.slot(target).volume);
+                self.packaged += packaged;
+                self.slot(target).volume -= packaged;
+                if self.slot(target).volume == 0 {
+                    self.slot(target).empty_out();
+                }
+                return changed(packaged, vec![target]);
+            }
+        }
+    }
+}
+
+fn changed(volume:u32, reservoirs:Vec<u32>) -> Vec<u32> {
+    if volume == 0 {
+        return vec![];
+    }
+    return reservoirs;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::Provenance;
+    use crate::MachineProfile;
+
+    fn loaded(inventory:&str) -> Machine {
+        let inventory = crate::parser::parse_inventory(inventory.to_string()).unwrap();
+        let state = ChemState::from_inventory(&inventory, &MachineProfile::default()).unwrap();
+        return Machine::new(&state, &[50, 50]);
+    }
+
+    fn action(step:Step) -> Action {
+        return Action::new(step, &Provenance::default());
+    }
+
+    #[test]
+    fn replay_follows_every_reservoir() {
+        let mut machine = loaded("1=30:hydrogen;,2=20:carbon;");
+        let transfer = action(Step::Transfer{amount:10, source:1, target:2});
+        assert_eq!(machine.describe(&transfer), "transfer 10u hydrogen r1->r2");
+        assert_eq!(machine.apply(&transfer), vec![1, 2]);
+        assert_eq!((machine.reservoirs[1].volume, machine.reservoirs[1].contents_text()), (30, "carbon+hydrogen".to_string()));
+        assert_eq!(machine.apply(&action(Step::Heat{temp:374, target:2})), Vec::<u32>::new());
+        let eject = action(Step::EjectDownTo{target:1, amount:5});
+        assert_eq!(machine.describe(&eject), "eject r1 down to 5u");
+        assert_eq!(machine.apply(&eject), vec![1]);
+        let pill = action(Step::CreatePill{target:2, amount:30});
+        assert_eq!(machine.describe(&pill), "press a 30u pill of carbon+hydrogen from r2");
+        machine.apply(&pill);
+        assert_eq!((machine.packaged, machine.ejected), (30, 15));
+        assert_eq!((machine.reservoirs[1].contents_text(), machine.reservoirs[1].temp), ("empty".to_string(), None));
+    }
+
+    #[test]
+    fn instructions_say_what_is_really_moved() {
+        let mut machine = loaded("1=30:hydrogen;,2=20:carbon;");
+        let transfer = action(Step::Transfer{amount:100, source:1, target:2});
+        assert_eq!(machine.instruct(&transfer), Some("Pour 30u of hydrogen from beaker 1 into beaker 2".to_string()));
+        machine.apply(&transfer);
+        assert_eq!(machine.instruct(&action(Step::EjectDownTo{target:2, amount:60})), None);
+        assert_eq!(machine.instruct(&action(Step::EjectDownTo{target:2, amount:40})), Some("Pour 10u out of beaker 2 and throw it away, leaving 40u".to_string()));
+        assert_eq!(machine.instruct(&action(Step::CreatePill{target:2, amount:100})), Some("Make a 50u pill from beaker 2".to_string()));
+        assert_eq!(machine.reservoirs[1].english(), "the carbon and hydrogen mix");
+    }
+}
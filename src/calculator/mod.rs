@@ -1,37 +1,67 @@
-use crate::{Chemical, ChemToken, NumberToken};
-use std::collections::{HashMap, HashSet};
+use crate::{BuildOrder, Chemical, ChemToken, NumberToken, PlanError, PlanWarning, MachineProfile};
+use crate::chemicals::{shares, Rounding};
+use std::collections::BTreeMap;
 use std::sync::atomic::AtomicU32;
 
-#[derive(Debug, Clone, Default, Hash, PartialEq, Eq)]
-pub struct ActionChain {
-    intial_state:ChemState,
-    chain:Vec<Action>
-}
+mod search;
+mod report;
+mod machine;
 
-#[derive(Debug, Clone, Default, Hash, PartialEq, Eq)]
-pub struct AbstractActionChain {
-    chain:Vec<AbstractAction>
-}
+pub use report::Report;
+pub use machine::Machine;
 
+/// A step for the machine, with the part of the recipe it was planned for
 #[derive(Debug, Clone, Hash, PartialEq, Eq)]
-pub enum Action {
+pub struct Action {
+    pub step:Step,
+    pub provenance:Provenance
+}
+
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq)]
+pub enum Step {
     Transfer{amount:u32, source:u32, target:u32},
     Heat{temp:u32, target:u32},
     Eject{target:u32},
+    /// compiled, but the planner has no use for it yet
+    #[allow(dead_code)]
     DumpByproduct{target:u32, remaining:u32},
     EjectDownTo{target:u32, amount:u32},
     CreateBottle{target:u32, amount:u32},
     CreatePill{target:u32, amount:u32}
 }
 
-#[derive(Debug, Clone, Hash, PartialEq, Eq)]
-pub enum AbstractAction {
-    Combine{vec:Vec<ChemToken>, temp:Option<u32>}
+/// Which part of the recipe an action was planned for. Housekeeping that is not for any one
+/// branch, like compacting the machine, has none.
+#[derive(Debug, Clone, Default, Hash, PartialEq, Eq)]
+pub struct Provenance {
+    /// id of the `ChemTreeBranch` being mixed or packaged
+    pub branch:Option<u32>,
+    /// the characters of the recipe the branch was written as
+    pub span:Option<(u32, u32)>,
+    /// the innermost substitute the branch is, or was expanded from
+    pub substitute:Option<String>
+}
+
+impl Provenance {
+    fn of(branch:&ChemTreeBranch) -> Provenance {
+        return Provenance {
+            branch:Some(branch.id),
+            span:branch.chem.source.span,
+            substitute:branch.chem.source.substitute.clone()
+        };
+    }
+}
+
+impl Action {
+    pub fn new(step:Step, provenance:&Provenance) -> Action {
+        return Action {step, provenance:provenance.clone()};
+    }
 }
 
 #[derive(Debug, Clone, Default, Hash, PartialEq, Eq)]
 pub struct ChemState {
-    chems:Vec<Reservoir>
+    chems:Vec<Reservoir>,
+    pub profile:MachineProfile
 }
 
 #[derive(Debug, Clone, Hash, PartialEq, Eq)]
@@ -40,69 +70,218 @@ pub struct Reservoir {
     reservoir_size:ReservoirSize
 }
 
-const NUM_RESERVOIRS:u32 = 10;
-const SMALL_RESERVOIR:u32 = 50;
-const LARGE_RESERVOIR:u32 = 100;
+/// What the planner minimises when asked to optimize
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Objective {
+    /// length of the emitted bytecode
+    Size,
+    /// number of actions
+    Steps
+}
 
-#[derive(Debug, Clone, Hash, PartialEq, Eq)]
-enum ReservoirSize {
-    Empty,
-    Small,
-    Large
+impl std::str::FromStr for Objective {
+    type Err = String;
+
+    fn from_str(string:&str) -> Result<Objective, String> {
+        match string {
+            "size" => Ok(Objective::Size),
+            "steps" => Ok(Objective::Steps),
+            _ => Err(format!("unknown objective {}, expected size or steps", string))
+        }
+    }
 }
 
-impl ReservoirSize {
-    fn get_size(&self) -> u32{
-        match self {
-            ReservoirSize::Empty => 0,
-            ReservoirSize::Small => SMALL_RESERVOIR,
-            ReservoirSize::Large => LARGE_RESERVOIR
+pub type CostFn<'a> = Box<dyn Fn(&Vec<Action>) -> usize + 'a>;
+
+/// Scores whole action sequences so the planner can pick the cheapest mixing order it finds.
+/// `limit` caps the number of mixing steps tried per batch, keeping the search time bounded.
+pub struct Optimizer<'a> {
+    pub cost:CostFn<'a>,
+    pub limit:usize
+}
+
+/// How the product leaves the machine
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Packaging {
+    #[default]
+    Pill,
+    Vial,
+    /// left in its reservoir
+    Keep
+}
+
+impl std::str::FromStr for Packaging {
+    type Err = String;
+
+    fn from_str(string:&str) -> Result<Packaging, String> {
+        match string {
+            "pill" => Ok(Packaging::Pill),
+            "vial" => Ok(Packaging::Vial),
+            "keep" => Ok(Packaging::Keep),
+            _ => Err(format!("unknown output {}, expected pill, vial or keep", string))
         }
     }
+}
 
-    fn fit(size:u32) -> ReservoirSize {
-        if size == 0 {
-            return ReservoirSize::Empty;
-        } else if size <= 50 {
-            return ReservoirSize::Small;
-        } else if size <= 100 {
-            return ReservoirSize::Large;
-        } else {
-            panic!("No reservoir large enough to fit")
+/// Packaging for the product. Without a `dose` every unit is as large as the machine makes them
+/// and the whole product is packaged, with one only full doses are made and the rest is left in
+/// the reservoir. `count` is the number of units wanted over all batches, by default as many as
+/// the product allows. Both are ignored when the product is kept.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Output {
+    pub packaging:Packaging,
+    pub dose:Option<u32>,
+    pub count:Option<u32>
+}
+
+impl Output {
+    fn unit_name(&self) -> &'static str {
+        match self.packaging {
+            Packaging::Pill => "pill",
+            Packaging::Vial => "vial",
+            Packaging::Keep => "reservoir"
+        }
+    }
+
+    /// the largest unit the machine makes, 0 when the product is kept
+    fn max_unit(&self, profile:&MachineProfile) -> u32 {
+        match self.packaging {
+            Packaging::Pill => profile.max_pill,
+            Packaging::Vial => profile.max_vial,
+            Packaging::Keep => 0
+        }
+    }
+
+    pub fn validate(&self, profile:&MachineProfile) -> Result<(), PlanError> {
+        if let Some(dose) = self.dose {
+            let max = self.max_unit(profile);
+            if self.packaging != Packaging::Keep && (dose == 0 || dose > max) {
+                return Err(PlanError::BadDose{dose, max, unit:self.unit_name()});
+            }
+        }
+        return Ok(());
+    }
+
+    /// Package the `product` units in `target`, or as many as `units_left` allows. Returns how
+    /// much is left behind.
+    fn package(&self, target:u32, product:u32, units_left:&mut Option<u32>, actions:&mut Vec<Action>, profile:&MachineProfile, provenance:&Provenance) -> u32 {
+        let amounts = self.amounts(product, units_left, profile);
+        for amount in &amounts {
+            actions.push(Action::new(self.step(target, *amount), provenance));
+        }
+        return product - product.min(amounts.iter().sum());
+    }
+
+    /// the size of each unit to package `product` units in, as many as `units_left` allows
+    fn amounts(&self, product:u32, units_left:&mut Option<u32>, profile:&MachineProfile) -> Vec<u32> {
+        if self.packaging == Packaging::Keep {
+            return vec![];
+        }
+        let max = self.max_unit(profile);
+        let (amount, possible) = match self.dose {
+            Some(dose) => (dose, product / dose),
+            None => (max, product.div_ceil(max).max(1))
+        };
+        let units = match units_left {
+            Some(left) => possible.min(*left),
+            None => possible
+        };
+        if let Some(left) = units_left {
+            *left -= units;
+        }
+        return vec![amount; units as usize];
+    }
+
+    fn step(&self, target:u32, amount:u32) -> Step {
+        match self.packaging {
+            Packaging::Vial => return Step::CreateBottle{target, amount},
+            _ => return Step::CreatePill{target, amount}
         }
     }
 }
 
-impl Reservoir {
-    pub fn add(&mut self, chem:&ChemToken) {
+/// Everything `compute_actions` planned
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Plan {
+    pub actions:Vec<Action>,
+    /// beaker each reservoir needs
+    pub sizes:Vec<u32>,
+    /// product left behind after packaging, by (1-indexed) reservoir
+    pub remainders:Vec<(u32, ChemToken)>,
+    /// the machine once the plan has run
+    pub state:ChemState
+}
 
+/// Base reagents pinned to (1-indexed) reservoirs, matching how the machine is pre-loaded
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Layout {
+    pub pins:Vec<(String, u32)>
+}
+
+impl Layout {
+    fn slot_of(&self, name:&str) -> Option<u32> {
+        for (pinned, slot) in &self.pins {
+            if pinned.eq_ignore_ascii_case(name) {
+                return Some(*slot);
+            }
+        }
+        return None;
     }
 
-    pub fn new(contents:&ChemToken) -> Reservoir {
-        Reservoir {contents:Some(contents.clone()), reservoir_size:ReservoirSize::fit(contents.size())}
+    /// reject pins that cannot all hold at once
+    pub fn validate(&self, profile:&MachineProfile) -> Result<(), PlanError> {
+        for i in 0..self.pins.len() {
+            let (name, slot) = &self.pins[i];
+            if *slot == 0 || *slot > profile.reservoirs {
+                return Err(PlanError::LayoutOutOfRange{reagent:name.clone(), reservoir:*slot, reservoirs:profile.reservoirs});
+            }
+            for (other_name, other_slot) in &self.pins[..i] {
+                if other_name.eq_ignore_ascii_case(name) && other_slot != slot {
+                    return Err(PlanError::LayoutPinnedTwice{reagent:name.clone(), first:*other_slot, second:*slot});
+                }
+                if !other_name.eq_ignore_ascii_case(name) && other_slot == slot {
+                    return Err(PlanError::LayoutSlotConflict{first:other_name.clone(), second:name.clone(), reservoir:*slot});
+                }
+            }
+        }
+        return Ok(());
     }
+}
 
-    pub fn empty() -> Reservoir {
-        Reservoir {contents:None, reservoir_size:ReservoirSize::Empty}
+/// capacity of the beaker a reservoir needs, 0 when it needs none
+#[derive(Debug, Clone, Hash, PartialEq, Eq)]
+struct ReservoirSize(u32);
+
+impl ReservoirSize {
+    fn get_size(&self) -> u32{
+        return self.0;
     }
 
-    pub fn upgrade_size(&mut self) -> Result<(), String> {
-        match self.reservoir_size {
-            ReservoirSize::Empty => {
-                self.reservoir_size = ReservoirSize::Small;
-                return Ok(());
-            },
-            ReservoirSize::Small => {
-                self.reservoir_size = ReservoirSize::Large;
-                return Ok(());
-            },
-            ReservoirSize::Large => return Err("Tried to upgrade large reservoir: Insufficient space!".to_string())
+    fn fit(size:u32, chem:&ChemToken, profile:&MachineProfile) -> Result<ReservoirSize, PlanError> {
+        match profile.fit(size) {
+            Some(beaker) => return Ok(ReservoirSize(beaker)),
+            None => return Err(PlanError::NoReservoirLargeEnough{node:chem.as_text(), size, largest:profile.largest_beaker(), reservoir:None})
         }
     }
+}
+
+impl Reservoir {
+    pub fn new(contents:&ChemToken, profile:&MachineProfile) -> Result<Reservoir, PlanError> {
+        Ok(Reservoir {contents:Some(contents.clone()), reservoir_size:ReservoirSize::fit(contents.size(), contents, profile)?})
+    }
+
+    /// holding `contents` without a beaker yet, see `ChemState::spread`
+    fn unfitted(contents:&ChemToken) -> Reservoir {
+        Reservoir {contents:Some(contents.clone()), reservoir_size:ReservoirSize(0)}
+    }
 
-    pub fn replace(&mut self, chem:&ChemToken) -> Result<(), String> {
+    pub fn empty() -> Reservoir {
+        Reservoir {contents:None, reservoir_size:ReservoirSize(0)}
+    }
+
+    pub fn replace(&mut self, chem:&ChemToken, profile:&MachineProfile) -> Result<(), PlanError> {
         if self.reservoir_size.get_size() < chem.combine_size() {
-            self.reservoir_size = ReservoirSize::fit(chem.combine_size());
+            self.reservoir_size = ReservoirSize::fit(chem.combine_size(), chem, profile)?;
         }
         self.contents = Some(chem.clone());
         return Ok(());
@@ -112,6 +291,18 @@ impl Reservoir {
         self.contents = None;
     }
 
+    /// add `amount` more of what the reservoir already holds, moving to a bigger beaker if needed
+    pub fn fill(&mut self, amount:u32, profile:&MachineProfile) -> Result<(), PlanError> {
+        let contents = self.contents.as_mut().unwrap();
+        let size = contents.size() + amount;
+        contents.quantity = NumberToken::Constant(size);
+        contents.concrete_quantity = Some(size);
+        if self.reservoir_size.get_size() < size {
+            self.reservoir_size = ReservoirSize::fit(size, contents, profile)?;
+        }
+        return Ok(());
+    }
+
     pub fn reduce(&mut self, amount:u32) {
         *self.contents.as_mut().unwrap().concrete_quantity.as_mut().unwrap() -= amount;
         self.contents.as_mut().unwrap().set_children_abstract();
@@ -119,72 +310,118 @@ impl Reservoir {
         //     self.clear();
         // }
     }
-
-    pub fn multiply(&mut self, amount:u32) {
-        if self.contents.is_none() {
-            return;
-        }
-        *self.contents.as_mut().unwrap().concrete_quantity.as_mut().unwrap()*=amount;
-        self.contents.as_mut().unwrap().quantity = NumberToken::Constant(self.contents.as_ref().unwrap().concrete_quantity.unwrap());
-        if self.reservoir_size.get_size() < self.contents.as_ref().unwrap().size() {
-            self.reservoir_size = ReservoirSize::fit(self.contents.as_ref().unwrap().size());
-        }
-    }
 }
 
 impl ChemState {
-    pub fn multiply(&mut self, amount:u32) {
-        for reservoir in &mut self.chems {
-            reservoir.multiply(amount);
+    /// Give every loaded reservoir a beaker. Contents too big for the largest beaker are spread
+    /// evenly over as many empty reservoirs as they need.
+    fn spread(&mut self) -> Result<(), PlanError> {
+        let largest = self.profile.largest_beaker();
+        let loaded:Vec<usize> = (0..self.chems.len()).filter(|i| self.chems[*i].contents.is_some()).collect();
+        let mut needed = self.count_nonempty();
+        for index in loaded {
+            let contents = self.chems[index].contents.clone().unwrap();
+            let total = contents.size();
+            let parts = total.div_ceil(largest).max(1);
+            needed += parts - 1;
+            for part in 0..parts {
+                let size = total / parts + if part < total % parts {1} else {0};
+                let mut chem = contents.clone();
+                chem.quantity = NumberToken::Constant(size);
+                chem.concrete_quantity = Some(size);
+                let target = match part {
+                    0 => index,
+                    _ => match self.first_empty() {
+                        Some(empty) => empty,
+                        None => return Err(PlanError::TooManyChemicals{count:needed as usize, reservoirs:self.profile.reservoirs})
+                    }
+                };
+                self.chems[target] = Reservoir::new(&chem, &self.profile).map_err(|err| err.in_reservoir(target as u32 + 1))?;
+            }
         }
+        return Ok(());
     }
 
-    pub fn to_text(&self) -> String{
-        let mut text = "[".to_string();
+    pub fn find_chem(&self, chem:&Chemical) -> Option<usize> {
         for i in 0..self.chems.len() {
-            let name:String = if self.chems[i].contents.is_some() {self.chems[i].contents.as_ref().unwrap().chemical.name.as_ref().unwrap_or(&"None".to_string()).to_string()} else {"None".to_string()};
-            let quantity:NumberToken = if self.chems[i].contents.is_some() {self.chems[i].contents.as_ref().unwrap().quantity.clone()} else {NumberToken::default()};
-            text = format!("{},  r{}:({}) {}", text, i+1,quantity.as_text(), name, );
+            if let Some(contents) = &self.chems[i].contents {
+                if &contents.chemical == chem {
+                    return Some(i);
+                }
+            }
         }
-        return format!("{}]",text);
+        return None;
     }
 
-    pub fn size(&self) -> u32 {
-        let mut sum = 0;
-        for reservoir in &self.chems {
-            if reservoir.contents.is_some() {
-                sum += reservoir.contents.as_ref().unwrap().size();
+    /// the reservoir holding `chem` with the smallest quantity that still covers `amount`, so
+    /// partial stocks are drawn down before larger ones are broken into
+    pub fn find_chem_with(&self, chem:&Chemical, amount:u32) -> Option<usize> {
+        let mut best:Option<(usize, u32)> = None;
+        for i in 0..self.chems.len() {
+            if let Some(contents) = &self.chems[i].contents {
+                let quantity = contents.size();
+                let smaller = best.is_none_or(|(_, best_quantity)| quantity < best_quantity);
+                if &contents.chemical == chem && quantity >= amount && smaller {
+                    best = Some((i, quantity));
+                }
             }
         }
-        return sum;
+        return best.map(|(i, _)| i);
     }
 
-    pub fn find_chem(&self, chem:&Chemical) -> Option<usize> {
+    /// Where to draw `amount` of `chem` from, as (reservoir, amount) pairs. A single reservoir is
+    /// used when one holds enough, otherwise the amount is gathered from several in order.
+    pub fn draws_for(&self, chem:&Chemical, amount:u32) -> Option<Vec<(usize, u32)>> {
+        if let Some(index) = self.find_chem_with(chem, amount) {
+            return Some(vec![(index, amount)]);
+        }
+        let mut draws = vec![];
+        let mut remaining = amount;
         for i in 0..self.chems.len() {
-            let reschem = &self.chems[i].contents;
-            if reschem.is_some() {
-                if &reschem.as_ref().unwrap().chemical == chem {
-                    return Some(i);
+            if let Some(contents) = &self.chems[i].contents {
+                if &contents.chemical == chem && contents.size() > 0 && remaining > 0 {
+                    let drawn = remaining.min(contents.size());
+                    draws.push((i, drawn));
+                    remaining -= drawn;
                 }
             }
         }
-        return None;
+        if remaining > 0 {
+            return None;
+        }
+        return Some(draws);
     }
 
-    pub fn new(reservoirs:&Vec<Reservoir>) -> ChemState {
-        let mut self_reservoirs = reservoirs.clone();
-        while self_reservoirs.len() < NUM_RESERVOIRS as usize {
+    pub fn new(reservoirs:&[Reservoir], profile:&MachineProfile) -> ChemState {
+        let mut self_reservoirs = reservoirs.to_vec();
+        while self_reservoirs.len() < profile.reservoirs as usize {
             self_reservoirs.push(Reservoir::empty());
         }
-        return ChemState {chems:self_reservoirs};
+        return ChemState {chems:self_reservoirs, profile:profile.clone()};
+    }
+
+    /// build a state from reservoirs the user has already loaded, as (1-indexed slot, contents)
+    pub fn from_inventory(inventory:&Vec<(u32, ChemToken)>, profile:&MachineProfile) -> Result<ChemState, PlanError> {
+        let mut state = ChemState::new(&[], profile);
+        for (slot, chem) in inventory {
+            if *slot == 0 || *slot > profile.reservoirs {
+                return Err(PlanError::NoSuchReservoir{reservoir:*slot, reservoirs:profile.reservoirs});
+            }
+            let index = *slot as usize - 1;
+            if state.chems[index].contents.is_some() {
+                return Err(PlanError::ReservoirLoadedTwice{reservoir:*slot});
+            }
+            state.chems[index] = Reservoir::new(chem, profile).map_err(|err| err.in_reservoir(*slot))?;
+        }
+        return Ok(state);
     }
 
     pub fn get(&self, index:usize) -> Reservoir {
         return self.chems.get(index).unwrap().clone();
     }
 
-    pub fn replace(&mut self, index:usize, chem:&ChemToken) {
-        self.chems.get_mut(index).unwrap().replace(chem).unwrap();
+    pub fn replace(&mut self, index:usize, chem:&ChemToken) -> Result<(), PlanError> {
+        return self.chems.get_mut(index).unwrap().replace(chem, &self.profile).map_err(|err| err.in_reservoir(index as u32 + 1));
     }
 
     pub fn clear(&mut self, index:usize) {
@@ -195,14 +432,38 @@ impl ChemState {
         self.chems.get_mut(index).unwrap().reduce(amount);
     }
 
-    pub fn first_empty(&self) -> usize {
+    pub fn first_empty(&self) -> Option<usize> {
         for i in 0..self.chems.len() {
             let reservoir = &self.chems[i];
             if reservoir.contents.is_none() {
-                return i;
+                return Some(i);
+            }
+        }
+        return None;
+    }
+
+    pub fn first_drained(&self, mix_reservoirs:&[usize]) -> Option<usize> {
+        for i in mix_reservoirs {
+            if let Some(contents) = &self.chems[*i].contents {
+                if contents.size() == 0 {
+                    return Some(*i);
+                }
+            }
+        }
+        return None;
+    }
+
+    /// reservoirs that cannot take a new mix, either holding something or drained outside `mix_reservoirs`
+    pub fn count_unavailable(&self, mix_reservoirs:&[usize]) -> u32 {
+        let mut count = 0;
+        for i in 0..self.chems.len() {
+            if let Some(contents) = &self.chems[i].contents {
+                if contents.size() > 0 || !mix_reservoirs.contains(&i) {
+                    count += 1;
+                }
             }
         }
-        panic!("No empty reservoirs!");
+        return count;
     }
 
     pub fn count_nonempty(&self) -> u32 {
@@ -215,13 +476,6 @@ impl ChemState {
         return count;
     }
 
-    pub fn pad(&mut self) {
-        self.chems.retain(|x| x.contents.is_some());
-        while self.chems.len() < NUM_RESERVOIRS as usize {
-            self.chems.push(Reservoir::empty());
-        }
-    }
-
     pub fn get_sizes(&self) -> Vec<u32> {
         let mut sizes = vec![];
         for reservoir in &self.chems {
@@ -234,7 +488,19 @@ impl ChemState {
 #[derive(Debug, Clone, Default, Hash, PartialEq, Eq)]
 pub struct ChemTree {
     pub initial_state:ChemState,
-    root:ChemTreeBranch
+    products:Vec<Product>,
+    pub warnings:Vec<PlanWarning>,
+    rounding:Rounding
+}
+
+/// One recipe to make `batches` times. Each batch is made as the sub-batches in `roots`, one
+/// after the other, if it is too big to mix in one go.
+#[derive(Debug, Clone, Default, Hash, PartialEq, Eq)]
+struct Product {
+    /// the recipe as given, for error reporting
+    recipe:String,
+    roots:Vec<ChemTreeBranch>,
+    batches:u32
 }
 
 #[derive(Debug, Clone, Default, Hash, PartialEq, Eq)]
@@ -242,6 +508,8 @@ struct ChemTreeBranch {
     chem:ChemToken,
     children:Vec<ChemTreeBranch>,
     id:u32,
+    /// the build order hint of the nearest branch up the tree that gives one
+    build:BuildOrder
 }
 
 impl ChemTreeBranch {
@@ -265,46 +533,22 @@ impl ChemTreeBranch {
 
     }
 
-    pub fn remove_leaf(&mut self, branch:&ChemTreeBranch) -> bool {
+    pub fn remove_leaf(&mut self, branch:&ChemTreeBranch) -> Result<bool, PlanError> {
         if !branch.is_leaf() {
-            panic!("TRIED TO TRIM NON_LEAF");
+            return Err(PlanError::NotALeaf{node:branch.chem.as_text()});
         }
         let len = self.children.len();
         self.children.retain(|x| x.id!=branch.id);
         if len == self.children.len() { // nobody pruned
             for child in &mut self.children {
-                let res = child.remove_leaf(branch);
+                let res = child.remove_leaf(branch)?;
                 if res {
-                    return true;
+                    return Ok(true);
                 }
             }
-            return false;
+            return Ok(false);
         } else {
-            return true;
-        }
-    }
-
-    pub fn dissolve_branch(&mut self, branch:&ChemTreeBranch) -> bool {
-        let mut found_branch = None;
-        for child in  &self.children {
-            if child.id == branch.id { // found it
-                found_branch = Some(child.clone());
-            }
-        }
-        if found_branch.is_some() {
-            let child = found_branch.unwrap();
-            for child_child in &child.children {
-                self.children.push(child_child.clone());
-            }
-            self.children.retain(|x| x.id != child.id);
-            return true;
-        } else {
-            for child in  &mut self.children {
-                if child.dissolve_branch(&branch) {
-                    return true;
-                }
-            }
-            return false;
+            return Ok(true);
         }
     }
 
@@ -323,141 +567,351 @@ impl ChemTreeBranch {
         }
     }
 
-    pub fn simplify(&mut self) {
-        let mut all_chems = self.bucket_chems();
-        println!("{:?}", all_chems);
-        let mut mut_tree = self.clone();
-        // trim the basic chems first
-        let leaves = mut_tree.get_leaves();
-        for leaf in &leaves {
-            mut_tree.remove_leaf(leaf);
-        }
-        'main: while !mut_tree.children.is_empty() {
-            let chem_keys:Vec<Chemical> = all_chems.keys().map(|x| x.clone()).collect();
-            for chem in &chem_keys {
-                let branches = self.get_branches_with_chem(chem);
-                if branches.is_empty() {
-                    panic!("No branches with chem");
-                }
-                let mut selected_leaf = None;
-                for branch in &branches {
-                    if branch.is_leaf() {
-                        selected_leaf = Some(branch);
-                        break;
-                    }
-                }
-                if selected_leaf.is_some() {
-                    let selected_leaf = selected_leaf.unwrap();
-                    let mut new_chem_token = selected_leaf.chem.clone();
-                    for branch in &branches {
-                        if branch.id == selected_leaf.id {
-                            continue;
-                        }
-                        new_chem_token.combine(&branch.chem);
-                        self.dissolve_branch(branch);
-                        mut_tree.dissolve_branch(branch);
-                    }
-                    let counted_amount = &all_chems[chem];
-                    assert_eq!(counted_amount.concrete_quantity.unwrap(), new_chem_token.concrete_quantity.unwrap());
-                    println!("Adding {:?} to {}", &new_chem_token, selected_leaf.id);
-                    if !self.replace_chem_in_branch(&new_chem_token, selected_leaf.id) {
-                        panic!("Missing leaf");
-                    }
-                    all_chems.remove(chem);
-                    continue 'main;
+    /// Build each intermediate that is used in several places once, in the combined volume. The
+    /// copy with the lowest id is grown to the total and the others lose their children, so their
+    /// consumers draw from it like from a loaded intermediate. A merge is only kept if the combined
+    /// volume fits in a beaker and every base reagent is used exactly as much as before, since
+    /// rounding fractions of a larger volume can change how much of each is drawn.
+    pub fn simplify(&mut self, largest_beaker:u32, rounding:Rounding) {
+        loop {
+            let usage = self.base_usage();
+            let mut merged = false;
+            for chemical in self.shared_intermediates() {
+                let mut trial = self.clone();
+                if trial.merge(&chemical, rounding) <= largest_beaker && trial.base_usage() == usage {
+                    *self = trial;
+                    merged = true;
+                    break;
                 }
             }
-            let leaves = mut_tree.get_leaves();
-            for leaf in &leaves {
-                mut_tree.remove_leaf(leaf);
+            if !merged {
+                return;
             }
         }
-        if !all_chems.is_empty() {
-            println!("Missing chems: {:?}", all_chems);
-        }
-    } 
+    }
 
-    fn bucket_chems(&self) -> HashMap<Chemical, ChemToken> {
-        let mut buckets = HashMap::new();
-        self.bucket_chems_recursive(&mut buckets);
-        return buckets;
+    /// intermediates built more than once below the root, the ones with the biggest trees first so
+    /// anything shared inside them is merged along with them
+    fn shared_intermediates(&self) -> Vec<Chemical> {
+        let mut counts:BTreeMap<Chemical, (u32, u32)> = BTreeMap::new();
+        for child in &self.children {
+            child.count_intermediates(&mut counts);
+        }
+        let mut shared:Vec<(Chemical, u32)> = counts.into_iter().filter(|(_, (count, _))| *count > 1).map(|(chemical, (_, branches))| (chemical, branches)).collect();
+        shared.sort_by_key(|(_, branches)| std::cmp::Reverse(*branches));
+        return shared.into_iter().map(|(chemical, _)| chemical).collect();
     }
 
-    fn bucket_chems_recursive(&self,buckets:&mut HashMap<Chemical, ChemToken>) {
-        if !buckets.contains_key(&self.chem.chemical) {
-            buckets.insert(self.chem.chemical.clone(), self.chem.clone());
+    /// occurrences and branch count of every intermediate in the tree
+    fn count_intermediates(&self, counts:&mut BTreeMap<Chemical, (u32, u32)>) {
+        if self.is_leaf() {
+            return;
         }
-        let mut new_chem = self.chem.clone();
-        new_chem.combine(&buckets[&self.chem.chemical]);
-        buckets.insert(self.chem.chemical.clone(), new_chem);
+        counts.entry(self.chem.chemical.clone()).or_insert((0, self.get_branch_count())).0 += 1;
         for child in &self.children {
-            child.bucket_chems_recursive(buckets);
+            child.count_intermediates(counts);
         }
     }
 
-    pub fn is_leaf(&self) -> bool {
-        return self.children.is_empty();
+    /// merge every copy of `chemical` that is still built into the first, returning the volume of its mix
+    fn merge(&mut self, chemical:&Chemical, rounding:Rounding) -> u32 {
+        let mut copies:Vec<ChemTreeBranch> = self.get_branches_with_chem(chemical).into_iter().filter(|x| !x.is_leaf()).collect();
+        copies.sort_by_key(|x| x.id);
+        let total = copies.iter().map(|x| x.chem.size()).sum();
+        for copy in &copies[1..] {
+            self.get_branch_mut(copy.id).unwrap().children.clear();
+        }
+        let kept = self.get_branch_mut(copies[0].id).unwrap();
+        kept.chem.quantity = NumberToken::Constant(total);
+        kept.chem.set_concrete_quantity(total, rounding);
+        kept.concretize_quantites(rounding);
+        return kept.chem.combine_size();
+    }
+
+    /// total of each base reagent the tree draws
+    fn base_usage(&self) -> BTreeMap<Chemical, u32> {
+        let mut usage = BTreeMap::new();
+        for leaf in self.get_leaves() {
+            if leaf.chem.chemical.chemicals.is_empty() {
+                *usage.entry(leaf.chem.chemical.clone()).or_insert(0) += leaf.chem.size();
+            }
+        }
+        return usage;
     }
 
-    pub fn replace_chem_in_branch(&mut self, chem:&ChemToken, branch_id:u32) -> bool {
+    fn get_branch_mut(&mut self, branch_id:u32) -> Option<&mut ChemTreeBranch> {
         if self.id == branch_id {
-            self.chem = chem.clone();
-            return true;
+            return Some(self);
         }
         for child in &mut self.children {
-            let result = child.replace_chem_in_branch(chem, branch_id);
-            if result {
-                return true;
+            if let Some(branch) = child.get_branch_mut(branch_id) {
+                return Some(branch);
             }
         }
-        return false;
+        return None;
+    }
+
+    pub fn get_branch_count(&self) -> u32 {
+        return 1 + self.children.iter().map(|x| x.get_branch_count()).sum::<u32>();
     }
 
-    pub fn concretize_quantites (&mut self) {
+    pub fn is_leaf(&self) -> bool {
+        return self.children.is_empty();
+    }
+
+    pub fn concretize_quantites (&mut self, rounding:Rounding) {
         if self.chem.quantity.is_constant() {
-            self.chem.set_concrete_quantity(0);
+            self.chem.set_concrete_quantity(0, rounding);
         }
-        for child in &mut self.children {
-            child.chem.set_concrete_quantity(self.chem.concrete_quantity.unwrap());
-            child.concretize_quantites();
+        let quantities:Vec<&NumberToken> = self.children.iter().map(|x| &x.chem.quantity).collect();
+        let shares = shares(self.chem.size(), &quantities, rounding);
+        for (child, share) in self.children.iter_mut().zip(shares) {
+            child.chem.concrete_quantity = Some(share);
+            child.chem.concretize_chemicals(rounding);
+            child.concretize_quantites(rounding);
         }
     }
 }
 
 
 impl ChemTreeBranch {
-    fn deconstruct(token:&ChemToken, id_counter:&mut AtomicU32) -> ChemTreeBranch {
+    fn deconstruct(token:&ChemToken, build:BuildOrder, id_counter:&mut AtomicU32) -> Result<ChemTreeBranch, PlanError> {
         let mut children = vec![];
         let chem = token;
+        let build = chem.build.unwrap_or(build);
+        if !chem.chemical.chemicals.is_empty() {
+            chem.addition_order()?; // report contradictory constraints before planning
+        }
         for child in &chem.chemical.chemicals {
-            children.push(ChemTreeBranch::deconstruct(child, id_counter));
+            children.push(ChemTreeBranch::deconstruct(child, build, id_counter)?);
+        }
+        return Ok(ChemTreeBranch {chem:chem.clone(), children, id:id_counter.fetch_add(1, std::sync::atomic::Ordering::Relaxed), build});
+    }
+
+    /// Split every mix below this branch that is too big for the largest beaker, outermost first
+    /// so the parts of an inner mix are worked out from the part of its parent they go into.
+    /// The parts are siblings, and their consumer draws from all of them.
+    fn split_oversized(&mut self, profile:&MachineProfile, rounding:Rounding, id_counter:&mut AtomicU32) -> Result<(), PlanError> {
+        let mut children = vec![];
+        for child in std::mem::take(&mut self.children) {
+            for mut part in child.split(profile, rounding, id_counter)? {
+                part.split_oversized(profile, rounding, id_counter)?;
+                children.push(part);
+            }
+        }
+        self.children = children;
+        return Ok(());
+    }
+
+    /// Split this branch by volume into as few parts as fit the largest beaker. The quantity is
+    /// shared out evenly, the first parts taking the units left over, and everything inside each
+    /// part is worked out from its own quantity, rounded like any other recipe of that size.
+    /// Returns the branch itself if it already fits.
+    fn split(self, profile:&MachineProfile, rounding:Rounding, id_counter:&mut AtomicU32) -> Result<Vec<ChemTreeBranch>, PlanError> {
+        let largest_beaker = profile.largest_beaker();
+        let combined = self.chem.combine_size();
+        if combined <= largest_beaker {
+            return Ok(vec![self]);
+        }
+        let quantity = self.chem.size();
+        let fewest = combined.div_ceil(largest_beaker);
+        // everything the parts are made from is loaded at once, so it has to fit the machine
+        if fewest > profile.reservoirs {
+            return Err(PlanError::TooLargeForMachine{node:self.chem.as_text(), size:combined, parts:fewest, reservoirs:profile.reservoirs});
+        }
+        // rounding can make a part mix to more than its share, so take more parts until they all
+        // fit, up to twice the fewest
+        for part_count in fewest..=quantity.min(fewest * 2) {
+            let mut parts = vec![];
+            for part in 0..part_count {
+                let part_quantity = quantity / part_count + if part < quantity % part_count {1} else {0};
+                let mut branch = self.clone();
+                branch.chem.quantity = NumberToken::Constant(part_quantity);
+                branch.concretize_quantites(rounding);
+                if rounding == Rounding::Exact {
+                    if let Some((part, parent)) = branch.chem.inexact_part() {
+                        let (numerator, denominator) = part.quantity.fraction().unwrap();
+                        return Err(PlanError::InexactSplit{node:parent.as_text(), part:part.as_text(), parent:parent.size(), numerator, denominator});
+                    }
+                }
+                branch.renumber(id_counter);
+                parts.push(branch);
+            }
+            if parts.iter().all(|x| x.chem.combine_size() <= largest_beaker) {
+                return Ok(parts);
+            }
+        }
+        return Err(PlanError::NoReservoirLargeEnough{node:self.chem.as_text(), size:combined, largest:largest_beaker, reservoir:None});
+    }
+
+    /// The largest intermediates below this branch that can be mixed for `batches` batches in one
+    /// go, scaled up to that volume. Where a mix would overflow the largest beaker its children
+    /// are tried instead. Chemicals already in `state` are left to be drawn from there.
+    fn bulk_intermediates(&self, batches:u32, largest_beaker:u32, state:&ChemState, bulk:&mut Vec<ChemTreeBranch>) {
+        for child in &self.children {
+            if child.is_leaf() || state.find_chem(&child.chem.chemical).is_some() {
+                continue;
+            }
+            if child.largest_mix() * batches <= largest_beaker {
+                let mut branch = child.clone();
+                branch.scale(batches, 1);
+                branch.chem.quantity = NumberToken::Constant(branch.chem.size());
+                bulk.push(branch);
+            } else {
+                child.bulk_intermediates(batches, largest_beaker, state, bulk);
+            }
+        }
+    }
+
+    /// volume of the biggest mix made while building this branch
+    fn largest_mix(&self) -> u32 {
+        let inner = self.children.iter().filter(|x| !x.is_leaf()).map(|x| x.largest_mix()).max().unwrap_or(0);
+        return inner.max(self.chem.combine_size());
+    }
+
+    /// multiply every quantity by `numerator / denominator`, which must divide them exactly
+    fn scale(&mut self, numerator:u32, denominator:u32) {
+        scale_token(&mut self.chem, numerator, denominator);
+        for child in &mut self.children {
+            child.scale(numerator, denominator);
+        }
+    }
+
+    /// fresh ids for every branch, numbered like `deconstruct` so children come first
+    fn renumber(&mut self, id_counter:&mut AtomicU32) {
+        for child in &mut self.children {
+            child.renumber(id_counter);
         }
-        return ChemTreeBranch {chem:chem.clone(), children, id:id_counter.fetch_add(1, std::sync::atomic::Ordering::Relaxed)};
+        self.id = id_counter.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+    }
+}
+
+fn scale_token(chem:&mut ChemToken, numerator:u32, denominator:u32) {
+    chem.concrete_quantity = Some(chem.size() * numerator / denominator);
+    for inner in &mut chem.chemical.chemicals {
+        scale_token(inner, numerator, denominator);
     }
 }
 
 impl ChemTree {
-    pub fn deconstruct(token:&ChemToken) -> ChemTree {
-        let mut root = ChemTreeBranch::deconstruct(token, &mut AtomicU32::new(0));
-        root.concretize_quantites();
-        let initial_state = compute_initial_state(&root.chem);
-        return ChemTree {root, initial_state};
+    /// Trees for every product, given with the number of batches of it to make, and the machine
+    /// load they all need between them, with fractions rounded as `rounding` says
+    pub fn deconstruct(products:&Vec<(ChemToken, u32)>, layout:&Layout, profile:&MachineProfile, rounding:Rounding) -> Result<ChemTree, PlanError> {
+        let mut id_counter = AtomicU32::new(0);
+        let mut tree_products = vec![];
+        let mut warnings = vec![];
+        for (token, batches) in products {
+            let mut root = ChemTreeBranch::deconstruct(token, BuildOrder::Any, &mut id_counter)?;
+            root.concretize_quantites(rounding);
+            if rounding == Rounding::Exact {
+                if let Some((part, parent)) = root.chem.inexact_part() {
+                    let (numerator, denominator) = part.quantity.fraction().unwrap();
+                    return Err(PlanError::InexactSplit{node:parent.as_text(), part:part.as_text(), parent:parent.size(), numerator, denominator});
+                }
+            }
+            let mut errors = vec![];
+            check_fractions(&root.chem, &mut errors, &mut warnings);
+            if let Some(err) = errors.into_iter().next() {
+                return Err(err);
+            }
+            let mut roots = root.split(profile, rounding, &mut id_counter)?;
+            for root in &mut roots {
+                root.split_oversized(profile, rounding, &mut id_counter)?;
+            }
+            tree_products.push(Product {recipe:token.as_text(), roots, batches:*batches});
+        }
+        // split parts are rounded on their own, so the load comes from them rather than the recipes
+        let mut usage = BTreeMap::new();
+        for product in &tree_products {
+            for root in &product.roots {
+                let root_usage = match root.is_leaf() {
+                    true => BTreeMap::from([(root.chem.chemical.clone(), root.chem.size())]),
+                    false => root.base_usage()
+                };
+                for (chem, amount) in root_usage {
+                    *usage.entry(chem).or_insert(0) += amount * product.batches;
+                }
+            }
+        }
+        let initial_state = compute_initial_state(&usage, layout, profile)?;
+        return Ok(ChemTree {products:tree_products, initial_state, warnings, rounding});
+    }
+}
+
+/// The `$` parts of a group may not ask for more than all of it. Asking for less, or rounding the
+/// parts up to more than the group holds, only warns. Substitutes are trusted, their parts follow
+/// the reaction, which often makes less than goes in. Every group is checked, so `errors` gets
+/// each one that asks for too much.
+pub fn check_fractions(token:&ChemToken, errors:&mut Vec<PlanError>, warnings:&mut Vec<PlanWarning>) {
+    if token.chemical.substitute.is_some() {
+        return;
+    }
+    if let Some((numerator, denominator)) = token.fraction_sum() {
+        let node = token.as_text();
+        if numerator > denominator {
+            errors.push(PlanError::OverSubscribed{node, numerator, denominator});
+        } else {
+            if numerator < denominator {
+                warnings.push(PlanWarning::UnderSubscribed{node:node.clone(), numerator, denominator});
+            }
+            let total:u32 = token.chemical.chemicals.iter().map(|x| x.size()).sum();
+            if total > token.size() {
+                warnings.push(PlanWarning::RoundingOvershoot{node, excess:total - token.size()});
+            }
+        }
+    }
+    for chem in &token.chemical.chemicals {
+        check_fractions(chem, errors, warnings);
     }
 }
 
-pub fn compute_initial_state (final_chem:&ChemToken) -> ChemState {
-    let mut chem_map = HashMap::new();
-    count_raw_chems_recursive(&mut chem_map, final_chem);
-    let mut temps_map = HashSet::new();
-    get_temps_recursive(&mut temps_map, final_chem);
+/// Place every base reagent in its own reservoir, given the total of each that is needed.
+/// Reagents pinned by `layout` go to their slot, the rest fill the unpinned slots in name order.
+pub fn compute_initial_state (usage:&BTreeMap<Chemical, u32>, layout:&Layout, profile:&MachineProfile) -> Result<ChemState, PlanError> {
+    layout.validate(profile)?;
     let mut chems_vec = vec![];
-    for chem in chem_map.keys() {
-        chems_vec.push(ChemToken{quantity:NumberToken::Constant(*chem_map.get(chem).unwrap()), chemical:chem.clone(), concrete_quantity:Some(*chem_map.get(chem).unwrap()), ..Default::default()});
-    }   
-    return ChemState::new(&chems_vec.into_iter().map(|x| Reservoir::new(&x)).collect());
+    for (chem, amount) in usage {
+        chems_vec.push(ChemToken{quantity:NumberToken::Constant(*amount), chemical:chem.clone(), concrete_quantity:Some(*amount), ..Default::default()});
+    }
+    let mut reservoirs = vec![Reservoir::empty(); profile.reservoirs as usize];
+    let mut unpinned = vec![];
+    for chem in chems_vec {
+        match layout.slot_of(chem.chemical.name.as_ref().unwrap()) {
+            Some(slot) => reservoirs[slot as usize - 1] = Reservoir::unfitted(&chem),
+            None => unpinned.push(chem)
+        }
+    }
+    let free_slots:Vec<usize> = (0..profile.reservoirs as usize).filter(|i| layout.pins.iter().all(|(_, slot)| *slot as usize != i + 1)).collect();
+    if unpinned.len() > free_slots.len() {
+        if layout.pins.is_empty() {
+            return Err(PlanError::TooManyChemicals{count:unpinned.len(), reservoirs:profile.reservoirs});
+        }
+        return Err(PlanError::LayoutFull{unpinned:unpinned.len(), free:free_slots.len()});
+    }
+    for (chem, slot) in unpinned.iter().zip(free_slots) {
+        reservoirs[slot] = Reservoir::unfitted(chem);
+    }
+    let mut state = ChemState::new(&reservoirs, profile);
+    state.spread()?;
+    return Ok(state);
+}
+
+/// total of each base reagent needed to make every product, given with its number of batches
+pub fn base_usage(products:&Vec<(ChemToken, u32)>, rounding:Rounding) -> BTreeMap<Chemical, u32> {
+    let mut usage = BTreeMap::new();
+    for (token, batches) in products {
+        let mut token = token.clone();
+        token.set_concrete_quantity(0, rounding);
+        let mut chem_map = BTreeMap::new();
+        count_raw_chems_recursive(&mut chem_map, &token);
+        for (chem, amount) in chem_map {
+            *usage.entry(chem).or_insert(0) += amount * batches;
+        }
+    }
+    return usage;
 }
 
-fn count_raw_chems_recursive(chem_map:&mut HashMap<Chemical, u32>, chem:&ChemToken) {
+fn count_raw_chems_recursive(chem_map:&mut BTreeMap<Chemical, u32>, chem:&ChemToken) {
     if chem.chemical.name.is_some() {
         if !chem_map.contains_key(&chem.chemical) {
             chem_map.insert(chem.chemical.clone(), 0);
@@ -469,142 +923,479 @@ fn count_raw_chems_recursive(chem_map:&mut HashMap<Chemical, u32>, chem:&ChemTok
     }
 }
 
-fn get_temps_recursive(temps_map:&mut HashSet<u32>, chem:&ChemToken) {
-    if chem.chemical.temp.is_some() {
-        temps_map.insert(chem.chemical.temp.unwrap());
+/// Free up reservoirs mid-plan, returning whether any were freed. Drained reservoirs outside
+/// `mix_reservoirs` are released, since only mixes leave anything behind, and a chemical spread
+/// over several reservoirs is poured together where the largest beaker holds it, emptying the
+/// smaller stocks first. The pours are added to `actions`.
+fn compress_state(state:&mut ChemState, mix_reservoirs:&[usize], actions:&mut Vec<Action>) -> Result<bool, PlanError> {
+    let mut freed = false;
+    for i in 0..state.chems.len() {
+        if state.chems[i].contents.as_ref().is_some_and(|x| x.size() == 0) && !mix_reservoirs.contains(&i) {
+            state.clear(i);
+            freed = true;
+        }
     }
-    for next_chem in &chem.chemical.chemicals {
-        get_temps_recursive(temps_map, next_chem);
+    let largest = state.profile.largest_beaker();
+    loop {
+        let mut stocks:BTreeMap<Chemical, Vec<(u32, usize)>> = BTreeMap::new();
+        for i in 0..state.chems.len() {
+            if let Some(contents) = &state.chems[i].contents {
+                if contents.size() > 0 {
+                    stocks.entry(contents.chemical.clone()).or_default().push((contents.size(), i));
+                }
+            }
+        }
+        let mut pour = None;
+        for reservoirs in stocks.values_mut() {
+            reservoirs.sort();
+            if reservoirs.len() > 1 && reservoirs[0].0 + reservoirs[1].0 <= largest {
+                pour = Some((reservoirs[0], reservoirs[1].1));
+                break;
+            }
+        }
+        let ((amount, source), target) = match pour {
+            Some(pour) => pour,
+            None => return Ok(freed)
+        };
+        actions.push(Action::new(Step::Transfer{amount, source:source as u32 + 1, target:target as u32 + 1}, &Provenance::default()));
+        let stock = state.chems[source].contents.as_ref().is_some_and(|x| x.chemical.name.is_some());
+        if mix_reservoirs.contains(&source) && !stock {
+            // a mix may have left residue behind
+            actions.push(Action::new(Step::Eject{target:source as u32 + 1}, &Provenance::default()));
+        }
+        state.clear(source);
+        state.chems[target].fill(amount, &state.profile).map_err(|err| err.in_reservoir(target as u32 + 1))?;
+        freed = true;
+    }
+}
+
+/// Claim intermediates that are already sitting in the machine. Any branch whose chemical can be
+/// drawn from a reservoir loses its children, so it is trimmed like a base chemical and never built.
+fn claim_stocked(branch:&mut ChemTreeBranch, state:&ChemState, claimed:&mut BTreeMap<usize, u32>) {
+    for child in &mut branch.children {
+        if child.is_leaf() {
+            continue;
+        }
+        let needed = child.chem.size();
+        let mut stocked = None;
+        for i in 0..state.chems.len() {
+            if let Some(contents) = &state.chems[i].contents {
+                let available = contents.size() - claimed.get(&i).unwrap_or(&0);
+                if contents.chemical == child.chem.chemical && available >= needed {
+                    stocked = Some(i);
+                    break;
+                }
+            }
+        }
+        match stocked {
+            Some(i) => {
+                *claimed.entry(i).or_insert(0) += needed;
+                child.children.clear();
+            },
+            None => claim_stocked(child, state, claimed)
+        }
     }
 }
 
-fn compress_state(state:&mut ChemState, tree:&mut ChemTree) {
+/// total quantity of each chemical that has to be drawn from the machine to build `branch`
+fn count_draws(branch:&ChemTreeBranch, draws:&mut BTreeMap<Chemical, u32>) {
+    for child in &branch.children {
+        if child.is_leaf() {
+            *draws.entry(child.chem.chemical.clone()).or_insert(0) += child.chem.size();
+        } else {
+            count_draws(child, draws);
+        }
+    }
+}
 
+fn check_stock(tree:&ChemTreeBranch, state:&ChemState) -> Result<(), PlanError> {
+    let mut draws = BTreeMap::new();
+    count_draws(tree, &mut draws);
+    for (chemical, needed) in &draws {
+        let mut available = 0;
+        for reservoir in &state.chems {
+            if let Some(contents) = &reservoir.contents {
+                if &contents.chemical == chemical {
+                    available += contents.size();
+                }
+            }
+        }
+        if available < *needed {
+            let name = match &chemical.name {
+                Some(name) => name.clone(),
+                None => format!("{:?}", chemical.chemicals.iter().map(|x| x.as_text()).collect::<String>())
+            };
+            return Err(PlanError::NotEnoughStock{chemical:name, needed:*needed, available});
+        }
+    }
+    return Ok(());
 }
 
-pub fn compute_actions(tree:&ChemTree, times_produced:u32) -> (Vec<Action>, Vec<u32>) {
-    let mut state = tree.initial_state.clone();
-    while state.chems.len() > NUM_RESERVOIRS as usize {
-        println!("TOO MANY CHEMICALS");
-        panic!();
+/// Plan the actions to produce every product in `tree`, starting from the reservoirs in
+/// `initial_state`. This is usually `tree.initial_state`, but may be a partially prepared machine:
+/// intermediates that are already loaded are used instead of being rebuilt, only the needed
+/// volume is drawn, and any surplus is left where it is.
+///
+/// Products take turns, one batch of each at a time, all drawing on the same load.
+/// Without an `optimizer` the greedy planner's order is used, otherwise the cheapest order found.
+/// Each batch's product is packaged as `output` asks, with `count` applying to each product.
+/// Whatever is left over stays in its reservoir, which is not mixed in again.
+///
+/// With several batches, intermediates are mixed once for all of them where a beaker holds that
+/// much, and each batch draws its share. If that leaves too few reservoirs free, every batch is
+/// built from scratch instead.
+///
+/// The optimizer only sees one batch at a time, and an order that is cheaper for one batch can
+/// make later ones dearer, so the greedy plan for the whole series is kept when it costs no more.
+pub fn compute_actions(tree:&ChemTree, initial_state:&ChemState, optimizer:Option<&Optimizer>, output:&Output) -> Result<Plan, PlanError> {
+    let plan = plan_series(tree, initial_state, optimizer, output);
+    let optimizer = match optimizer {
+        Some(optimizer) => optimizer,
+        None => return plan
+    };
+    let greedy = plan_series(tree, initial_state, None, output);
+    if let (Ok(optimized), Ok(greedy_plan)) = (&plan, &greedy) {
+        if (optimizer.cost)(&greedy_plan.actions) <= (optimizer.cost)(&optimized.actions) {
+            return greedy;
+        }
     }
+    return plan;
+}
 
-    let allowed_mix_reservoirs_min_index;
-    if times_produced > 1 {
-        allowed_mix_reservoirs_min_index = find_intially_empty(&state);
-    } else {
-        allowed_mix_reservoirs_min_index = 0;
+/// `compute_actions` with every batch planned by the `optimizer` if there is one
+fn plan_series(tree:&ChemTree, initial_state:&ChemState, optimizer:Option<&Optimizer>, output:&Output) -> Result<Plan, PlanError> {
+    if tree.products.iter().any(|x| x.batches > 1) {
+        match plan_batches(tree, initial_state, optimizer, output, true) {
+            Err(PlanError::NoEmptyReservoir{..}) | Err(PlanError::NotEnoughReservoirs{..}) => {},
+            plan => return plan
+        }
+    }
+    return plan_batches(tree, initial_state, optimizer, output, false);
+}
+
+/// `compute_actions`, making intermediates for every batch at once first if `bulk` is set
+fn plan_batches(tree:&ChemTree, initial_state:&ChemState, optimizer:Option<&Optimizer>, output:&Output, bulk:bool) -> Result<Plan, PlanError> {
+    let mut state = initial_state.clone();
+    if state.chems.len() > state.profile.reservoirs as usize {
+        return Err(PlanError::TooManyChemicals{count:state.count_nonempty() as usize, reservoirs:state.profile.reservoirs});
     }
+    output.validate(&state.profile)?;
+
+    let mixes:u32 = tree.products.iter().map(|x| x.batches * x.roots.len() as u32).sum();
+    let mut mix_reservoirs = if mixes > 1 {
+        find_intially_empty(&state, &tree.products[0].roots[0])?
+    } else {
+        (0..state.chems.len()).collect()
+    };
     let mut actions = vec![];
-    for _ in 0..times_produced {
-        let mut mut_tree = tree.root.clone();
-        // mut_tree.simplify();
-        trim_basics(&mut mut_tree);
-        while !mut_tree.children.is_empty() {
-            compute_step(&mut state, &mut mut_tree, &mut actions, allowed_mix_reservoirs_min_index);
+    if bulk {
+        for product in tree.products.iter().filter(|x| x.batches > 1) {
+            for root in &product.roots {
+                let mut parts = vec![];
+                root.bulk_intermediates(product.batches, state.profile.largest_beaker(), &state, &mut parts);
+                for mut part in parts {
+                    check_stock(&part, &state)?;
+                    part.simplify(state.profile.largest_beaker(), tree.rounding);
+                    trim_basics(&mut part)?;
+                    match optimizer {
+                        Some(optimizer) => search::optimize_tree(&mut state, &mut part, &mut actions, &mix_reservoirs, optimizer)?,
+                        None => search::build_tree(&mut state, &mut part, &mut actions, &mix_reservoirs)?
+                    };
+                }
+            }
+        }
+    }
+    let mut remainders = vec![];
+    let mut units_left:Vec<Option<u32>> = tree.products.iter().map(|_| match output.packaging {
+        Packaging::Keep => None,
+        _ => output.count
+    }).collect();
+    let rounds = tree.products.iter().map(|x| x.batches).max().unwrap_or(0);
+    for round in 0..rounds {
+        for (product_index, product) in tree.products.iter().enumerate() {
+            if round >= product.batches {
+                continue;
+            }
+            for root in &product.roots {
+                let mut mut_tree = root.clone();
+                claim_stocked(&mut mut_tree, &state, &mut BTreeMap::new());
+                check_stock(&mut_tree, &state)?;
+                mut_tree.simplify(state.profile.largest_beaker(), tree.rounding);
+                trim_basics(&mut mut_tree)?;
+                if mut_tree.chem.chemical.chemicals.is_empty() {
+                    package_stock(&mut state, &mut_tree, output, &mut units_left[product_index], &mut actions)?;
+                    continue;
+                }
+                let output_reservoir = match optimizer {
+                    Some(optimizer) => search::optimize_tree(&mut state, &mut mut_tree, &mut actions, &mix_reservoirs, optimizer)?,
+                    None => search::build_tree(&mut state, &mut mut_tree, &mut actions, &mix_reservoirs)?
+                };
+                let output_reservoir_index = output_reservoir as usize - 1;
+                let made = state.get(output_reservoir_index).contents.unwrap();
+                // rounding the parts can mix more or less than the recipe asks for, so package what is there
+                let volume = made.combine_size();
+                let remainder = output.package(output_reservoir, volume, &mut units_left[product_index], &mut actions, &state.profile, &Provenance::of(root));
+                if remainder > 0 {
+                    let mut left = made.clone();
+                    left.quantity = NumberToken::Constant(remainder);
+                    left.concrete_quantity = Some(remainder);
+                    remainders.push((output_reservoir, left.clone()));
+                    left.set_children_abstract();
+                    state.chems[output_reservoir_index].contents = Some(left);
+                    mix_reservoirs.retain(|x| *x != output_reservoir_index);
+                } else {
+                    state.clear(output_reservoir_index);
+                }
+                for i in &mix_reservoirs {
+                    if state.chems[*i].contents.as_ref().is_some_and(|x| x.size() == 0) {
+                        state.clear(*i);
+                    }
+                }
+            }
         }
-        compute_step(&mut state, &mut mut_tree, &mut actions, allowed_mix_reservoirs_min_index); // final mix step
-        let output_chem = &tree.root.chem;
-        let output_reservoir_index = state.find_chem(&output_chem.chemical).unwrap();
-        actions.push(Action::CreatePill{target:output_reservoir_index as u32 + 1, amount:100});
-        state.clear(output_reservoir_index);
-        for i in allowed_mix_reservoirs_min_index..NUM_RESERVOIRS {
-            state.clear(i as usize);
+    }
+    for (product, left) in tree.products.iter().zip(units_left) {
+        if let (Some(left), Some(wanted)) = (left, output.count) {
+            if left > 0 {
+                return Err(PlanError::NotEnoughProduct{product:product.recipe.clone(), wanted, made:wanted - left, unit:output.unit_name()});
+            }
         }
     }
-    // if mut_tree.chem.chemical.temp.is_some() {
-    //     println!("\n\nSTATE: {:?}\n CHEM: {:?}", state, &mut_tree.chem.chemical);
-    //     let reservoir_index = state.find_chem(&mut_tree.chem.chemical).unwrap();
-    //     actions.push(Action::Heat{target:reservoir_index as u32, temp:mut_tree.chem.chemical.temp.unwrap()})
-    // }
-    
-    return (actions,state.get_sizes());
+
+    return Ok(Plan {actions, sizes:state.get_sizes(), remainders, state});
+}
+
+/// A base reagent is not mixed, so package it straight from the reservoir stocking it. The stock
+/// may hold more than the recipe asks for, so no unit takes more than is left of the quantity.
+fn package_stock(state:&mut ChemState, root:&ChemTreeBranch, output:&Output, units_left:&mut Option<u32>, actions:&mut Vec<Action>) -> Result<(), PlanError> {
+    let quantity = root.chem.size();
+    let stock = match state.find_chem_with(&root.chem.chemical, quantity) {
+        Some(stock) => stock,
+        None => return Err(PlanError::MissingIngredient{node:root.chem.as_text(), ingredient:root.chem.as_text(), action:actions.len()})
+    };
+    let provenance = Provenance::of(root);
+    let mut left = quantity;
+    for amount in output.amounts(quantity, units_left, &state.profile) {
+        let amount = amount.min(left);
+        actions.push(Action::new(output.step(stock as u32 + 1, amount), &provenance));
+        left -= amount;
+    }
+    state.reduce(stock, quantity - left);
+    return Ok(());
 }
 
-fn find_intially_empty(initial_state:&ChemState) -> u32 {
-    return initial_state.first_empty() as u32;
+/// reservoirs that start empty, and so can be cleared out between batches
+fn find_intially_empty(initial_state:&ChemState, root:&ChemTreeBranch) -> Result<Vec<usize>, PlanError> {
+    let empty:Vec<usize> = (0..initial_state.chems.len()).filter(|i| initial_state.chems[*i].contents.is_none()).collect();
+    if empty.is_empty() {
+        return Err(PlanError::NoEmptyReservoir{node:root.chem.as_text(), action:0});
+    }
+    return Ok(empty);
 }
 
-fn trim_basics(tree:&mut ChemTreeBranch) {
+fn trim_basics(tree:&mut ChemTreeBranch) -> Result<(), PlanError> {
     let leaves = tree.get_leaves();
     for leaf in &leaves {
-        tree.remove_leaf(leaf);
+        tree.remove_leaf(leaf)?;
     }
+    return Ok(());
 }
 
-fn emptied_chemicals(chem:&ChemToken, state:&ChemState, allowed_mix_reservoirs_min_index:u32) -> u32 {
+fn emptied_chemicals(chem:&ChemToken, state:&ChemState) -> u32 {
     let mut emptied_count = 0;
     for chemical in &chem.chemical.chemicals {
-        for reservoir in &state.chems {
-            if reservoir.contents.is_some(){
-                let reservoir_chemical = reservoir.contents.as_ref().unwrap();
-                if reservoir_chemical.chemical == chemical.chemical {
-                    if reservoir_chemical.concrete_quantity.unwrap() - chemical.concrete_quantity.unwrap() == 0 {
-                        emptied_count += 1;
-                    }
-                }
+        if let Some(index) = state.find_chem_with(&chemical.chemical, chemical.size()) {
+            if state.chems[index].contents.as_ref().unwrap().size() == chemical.size() {
+                emptied_count += 1;
             }
         }
     }
     return emptied_count;
 }
 
-fn compute_step(state:&mut ChemState, tree:&mut ChemTreeBranch, actions:&mut Vec<Action>, allowed_mix_reservoirs_min_index:u32) -> u32{
+fn ingredients_ready(chem:&ChemToken, state:&ChemState) -> bool {
+    return chem.chemical.chemicals.iter().all(|x| state.draws_for(&x.chemical, x.size()).is_some());
+}
+
+fn compute_step(state:&mut ChemState, tree:&mut ChemTreeBranch, actions:&mut Vec<Action>, mix_reservoirs:&[usize]) -> Result<u32, PlanError> {
+    let picked = ranked_leaves(tree, state).remove(0);
+    return mix_leaf(state, tree, actions, mix_reservoirs, &picked);
+}
+
+/// The leaves that may be mixed next, best first: only the leaves with the soonest build order
+/// hint and then the highest priority are allowed, and of those the ones that empty the most
+/// reservoirs are preferred
+fn ranked_leaves(tree:&ChemTreeBranch, state:&ChemState) -> Vec<ChemTreeBranch> {
     let mut leaves = tree.get_leaves();
+    // ties on priority and emptied reservoirs go to the lowest branch id, so plans are reproducible
     leaves.sort_by(|x1,x2| {
-        x2.chem.priority.cmp(&x1.chem.priority)
+        x2.chem.priority.cmp(&x1.chem.priority).then(x1.id.cmp(&x2.id))
     });
+    // merged intermediates are drawn by several branches, which wait until it has been mixed
+    let ready:Vec<ChemTreeBranch> = leaves.iter().filter(|x| ingredients_ready(&x.chem, state)).cloned().collect();
+    if !ready.is_empty() {
+        leaves = ready;
+    }
+    let soonest = leaves.iter().map(|x| x.build).max().unwrap();
+    leaves.retain(|x| x.build == soonest);
     let max_priority = leaves.first().unwrap().chem.priority;
     leaves.retain(|x| x.chem.priority == max_priority);
-    let mut picked = leaves.first().unwrap();
-    let mut max_emptied = emptied_chemicals(&picked.chem, state, allowed_mix_reservoirs_min_index);
-    for i in 1..leaves.len() {
-        let trial = leaves.get(i).unwrap();
-        let emptied = emptied_chemicals(&trial.chem,state, allowed_mix_reservoirs_min_index);
-        if emptied > max_emptied {
-            picked = trial;
-            max_emptied = emptied;
-        }
-    }
-    tree.remove_leaf(picked);
-    let mut chems = picked.chem.chemical.chemicals.clone();
-    chems.sort_by(|x1, x2| x2.priority.cmp(&x1.priority));
+    leaves.sort_by_key(|x| std::cmp::Reverse(emptied_chemicals(&x.chem, state)));
+    return leaves;
+}
+
+/// mix `picked` out of its ingredients, returning the (1-indexed) reservoir it ends up in
+fn mix_leaf(state:&mut ChemState, tree:&mut ChemTreeBranch, actions:&mut Vec<Action>, mix_reservoirs:&[usize], picked:&ChemTreeBranch) -> Result<u32, PlanError> {
+    tree.remove_leaf(picked)?;
+    let provenance = Provenance::of(picked);
+    let group = &picked.chem.chemical;
+    let may_go_first = picked.chem.may_go_first()?;
+    let chems:Vec<(usize, &ChemToken)> = picked.chem.addition_order()?.into_iter().map(|i| (i, &group.chemicals[i])).collect();
     // remove before finding an empty reservoir in case one of them opens up
-    for chem in &chems {
-        let reservoir_index = state.find_chem(&chem.chemical).unwrap();
-        state.reduce(reservoir_index, chem.concrete_quantity.unwrap());
+    let mut draws = vec![];
+    for (index, chem) in chems {
+        let chem_draws = match state.draws_for(&chem.chemical, chem.size()) {
+            Some(chem_draws) => chem_draws,
+            None => return Err(PlanError::MissingIngredient{node:picked.chem.as_text(), ingredient:chem.as_text(), action:actions.len()})
+        };
+        for (reservoir_index, amount) in chem_draws {
+            state.reduce(reservoir_index, amount);
+            draws.push((reservoir_index, amount, group.stage_of(index), may_go_first[index]));
+        }
     }
     let mut combine_reservoir = None;
-    for chem in &chems {
-        let reservoir_index = state.find_chem(&chem.chemical).unwrap();
+    for &(reservoir_index, amount, _, first) in &draws {
         let reservoir = state.get(reservoir_index);
-        if reservoir.contents.as_ref().unwrap().concrete_quantity.unwrap() == 0 && reservoir_index as u32 >= allowed_mix_reservoirs_min_index {
-            if combine_reservoir.is_none() {
-                actions.push(Action::EjectDownTo{amount:chem.concrete_quantity.unwrap(), target:reservoir_index as u32 + 1});
-                combine_reservoir = Some(reservoir_index);
-            }
+        // mixing in place puts the ingredient in first, so heat stages and ordering have to allow that
+        let empty = reservoir.contents.as_ref().unwrap().concrete_quantity.unwrap() == 0;
+        if empty && mix_reservoirs.contains(&reservoir_index) && first && combine_reservoir.is_none() {
+            actions.push(Action::new(Step::EjectDownTo{amount, target:reservoir_index as u32 + 1}, &provenance));
+            combine_reservoir = Some(reservoir_index);
         }
     }
 
     if combine_reservoir.is_none() {
-        combine_reservoir = Some(state.first_empty());
-    }   
-    let combine_reservoir = combine_reservoir.unwrap();
-
-    for chem in &chems {
-        let reservoir_index = state.find_chem(&chem.chemical).unwrap();
+        combine_reservoir = state.first_empty();
+    }
+    if combine_reservoir.is_none() {
+        // reuse a reservoir an earlier step drained, clearing out anything left behind
+        combine_reservoir = state.first_drained(mix_reservoirs);
+        if let Some(index) = combine_reservoir {
+            actions.push(Action::new(Step::Eject{target:index as u32 + 1}, &provenance));
+            state.clear(index);
+        }
+    }
+    let combine_reservoir = match combine_reservoir {
+        Some(index) => index,
+        None => return Err(PlanError::NoEmptyReservoir{node:picked.chem.as_text(), action:actions.len()})
+    };
+
+    let mut stages = group.stages.clone();
+    stages.sort_by_key(|(added, _)| *added);
+    let mut heated = 0;
+    for &(reservoir_index, amount, stage, _) in &draws {
+        while heated < stage {
+            actions.push(Action::new(Step::Heat{target:combine_reservoir as u32 + 1, temp:stages[heated].1}, &provenance));
+            heated += 1;
+        }
         if reservoir_index != combine_reservoir {
-            actions.push(Action::Transfer{amount:chem.concrete_quantity.unwrap(), target:combine_reservoir as u32 + 1, source:reservoir_index as u32 + 1});
-            if chem.concrete_quantity.unwrap() == 0 {
-                actions.push(Action::Eject{target:reservoir_index as u32 + 1});
+            actions.push(Action::new(Step::Transfer{amount, target:combine_reservoir as u32 + 1, source:reservoir_index as u32 + 1}, &provenance));
+            if amount == 0 {
+                actions.push(Action::new(Step::Eject{target:reservoir_index as u32 + 1}, &provenance));
                 state.clear(reservoir_index);
             }
         }
     }
-    if picked.chem.chemical.temp.is_some() {
-        actions.push(Action::Heat{target:combine_reservoir as u32 + 1, temp:picked.chem.chemical.temp.unwrap()});
+    for (_, temp) in &stages[heated..] {
+        actions.push(Action::new(Step::Heat{target:combine_reservoir as u32 + 1, temp:*temp}, &provenance));
+    }
+    if let Some(temp) = picked.chem.chemical.temp {
+        actions.push(Action::new(Step::Heat{target:combine_reservoir as u32 + 1, temp}, &provenance));
+    }
+
+    state.replace(combine_reservoir, &picked.chem)?;
+    return Ok(combine_reservoir as u32 + 1);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn branch(recipe:&str) -> ChemTreeBranch {
+        let (token, _) = crate::parser::parse(recipe.to_string()).unwrap();
+        let mut root = ChemTreeBranch::deconstruct(&token, BuildOrder::Any, &mut AtomicU32::new(0)).unwrap();
+        root.concretize_quantites(Rounding::Up);
+        return root;
+    }
+
+    fn built_copies(root:&ChemTreeBranch, name:&str) -> usize {
+        let (token, _) = crate::parser::parse(format!("1:*{};", name)).unwrap();
+        return root.get_branches_with_chem(&token.chemical).iter().filter(|x| !x.is_leaf()).count();
+    }
+
+    #[test]
+    fn split_parts_fit_and_add_up() {
+        for (recipe, parts) in [("150:*METH;", 2), ("200:*METH;", 3), ("100:*METH;", 2), ("101:hydrogen;", 2), ("300:*OIL;", 5)] {
+            let root = branch(recipe);
+            let quantity = root.chem.size();
+            let split = root.split(&MachineProfile::default(), Rounding::Up, &mut AtomicU32::new(1000)).unwrap();
+            assert_eq!(split.len(), parts, "{}", recipe);
+            assert!(split.iter().all(|x| x.chem.combine_size() <= 100), "{}", recipe);
+            assert_eq!(split.iter().map(|x| x.chem.size()).sum::<u32>(), quantity, "{}", recipe);
+        }
+    }
+
+    #[test]
+    fn split_stops_at_what_the_machine_holds() {
+        let root = branch("4294967295:($/2:hydrogen;$/2:oxygen;)");
+        match root.split(&MachineProfile::default(), Rounding::Up, &mut AtomicU32::new(1000)) {
+            Err(PlanError::TooLargeForMachine{parts, reservoirs, ..}) => assert_eq!((parts, reservoirs), (42949673, 10)),
+            other => panic!("expected the recipe to be too large, got {:?}", other.map(|x| x.len()))
+        }
+    }
+
+    #[test]
+    fn simplify_keeps_base_usage() {
+        // 10u and 5u of oil draw 5+3 of each reagent, and so does 15u mixed once
+        let mut root = branch("20:($/2:*OIL;$/2:($/2:*OIL;$/2:oxygen;))");
+        let usage = root.base_usage();
+        root.simplify(100, Rounding::Up);
+        assert_eq!(built_copies(&root, "OIL"), 1);
+        assert_eq!(root.base_usage(), usage);
+
+        // 1u of sulfuric acid rounds every part up to 1u, twice that is 2u of each, but 2u mixed once only needs 1u
+        let mut root = branch("2:($/2:*SULFURIC_ACID;$/2:($/1:*SULFURIC_ACID;))");
+        let usage = root.base_usage();
+        root.simplify(100, Rounding::Up);
+        assert_eq!(built_copies(&root, "SULFURIC_ACID"), 2);
+        assert_eq!(root.base_usage(), usage);
+    }
+
+    #[test]
+    fn compressing_only_ejects_mixes() {
+        let profile = MachineProfile::default();
+        for (inventory, ejects) in [("1=10:hydrogen;,2=20:hydrogen;", 0), ("1=10:*OIL;,2=20:*OIL;", 1)] {
+            let inventory = crate::parser::parse_inventory(inventory.to_string()).unwrap();
+            let mut state = ChemState::from_inventory(&inventory, &profile).unwrap();
+            let mut actions = vec![];
+            let every_reservoir:Vec<usize> = (0..state.chems.len()).collect();
+            assert!(compress_state(&mut state, &every_reservoir, &mut actions).unwrap());
+            assert_eq!(actions[0].step, Step::Transfer{amount:10, source:1, target:2});
+            assert_eq!(actions.iter().filter(|x| matches!(x.step, Step::Eject{..})).count(), ejects, "{:?}", inventory);
+            assert_eq!(state.count_nonempty(), 1);
+        }
     }
 
-    state.replace(combine_reservoir, &picked.chem);
-    return combine_reservoir as u32 + 1;
-}
\ No newline at end of file
+    #[test]
+    fn layout_pins_reagents_to_their_slots() {
+        let layout = Layout {pins:vec![("Nitrogen".to_string(), 5)]};
+        assert_eq!((layout.slot_of("nitrogen"), layout.slot_of("hydrogen")), (Some(5), None));
+        let reagent = |name:&str| Chemical {name:Some(name.to_string()), ..Default::default()};
+        let usage = BTreeMap::from([(reagent("hydrogen"), 10), (reagent("nitrogen"), 20), (reagent("oxygen"), 30)]);
+        let state = compute_initial_state(&usage, &layout, &MachineProfile::default()).unwrap();
+        let names:Vec<Option<String>> = state.chems.iter().map(|x| x.contents.as_ref().and_then(|x| x.chemical.name.clone())).collect();
+        assert_eq!(names[..5], [Some("hydrogen".to_string()), Some("oxygen".to_string()), None, None, Some("nitrogen".to_string())]);
+        let layout = Layout {pins:vec![("nitrogen".to_string(), 11)]};
+        assert_eq!(compute_initial_state(&usage, &layout, &MachineProfile::default()).map(|_| ()), Err(PlanError::LayoutOutOfRange{reagent:"nitrogen".to_string(), reservoir:11, reservoirs:10}));
+    }
+}
@@ -0,0 +1,83 @@
+use super::{ChemState, ChemTree, ChemTreeBranch, Machine, Plan};
+use crate::ChemToken;
+use crate::chemicals::FixedPoint;
+
+/// Where the loaded volume ends up once a plan has run, replayed on a `Machine`. Volumes include
+/// the surplus a mix makes over the quantity its recipe asks for.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Report {
+    /// every (1-indexed) reservoir's volume, with what the planner left in it if anything
+    pub contents:Vec<(u32, u32, Option<ChemToken>)>,
+    /// volume loaded before the plan runs
+    pub loaded:u32,
+    /// volume pressed into pills or bottled as the product
+    pub packaged:u32,
+    /// volume of the product left in its reservoir
+    pub kept:u32,
+    /// volume thrown away by `EjectDownTo` and `DumpByproduct`
+    pub ejected:u32,
+    /// volume pressed into side pills by `Eject`
+    pub emptied:u32,
+    /// each `$` node's children, shortened, and how far over (or under, if negative) their exact
+    /// share they are rounded to over the whole plan
+    pub rounding:Vec<(String, f64)>,
+    /// the fixed point all the volumes above are counted in
+    pub units:FixedPoint
+}
+
+impl Report {
+    pub fn new(tree:&ChemTree, initial_state:&ChemState, plan:&Plan) -> Report {
+        let mut machine = Machine::new(initial_state, &plan.sizes);
+        for action in &plan.actions {
+            machine.apply(action);
+        }
+        let volumes:Vec<u32> = machine.reservoirs.iter().map(|x| x.volume).collect();
+        let mut report = Report {
+            loaded:initial_state.chems.iter().map(|x| x.contents.as_ref().map(|x| x.size()).unwrap_or(0)).sum(),
+            packaged:machine.packaged,
+            ejected:machine.ejected,
+            emptied:machine.emptied,
+            units:machine.units,
+            ..Default::default()
+        };
+        for (reservoir, _) in &plan.remainders {
+            report.kept += volumes[*reservoir as usize - 1];
+        }
+        for (i, volume) in volumes.iter().enumerate() {
+            let contents = plan.state.chems[i].contents.clone().filter(|x| x.size() > 0);
+            report.contents.push((i as u32 + 1, *volume, contents));
+        }
+        for product in &tree.products {
+            for root in &product.roots {
+                add_rounding(root, product.batches, &mut report.rounding);
+            }
+        }
+        return report;
+    }
+
+    /// the share of the loaded volume that ends up as product, as a percentage
+    pub fn efficiency(&self) -> f64 {
+        if self.loaded == 0 {
+            return 0.0;
+        }
+        return (self.packaged + self.kept) as f64 * 100.0 / self.loaded as f64;
+    }
+}
+
+fn add_rounding(branch:&ChemTreeBranch, times:u32, rounding:&mut Vec<(String, f64)>) {
+    let mut excess = 0.0;
+    for child in &branch.children {
+        if let Some((numerator, denominator)) = child.chem.quantity.fraction() {
+            let exact = branch.chem.size() as f64 * numerator as f64 / denominator as f64;
+            excess += child.chem.size() as f64 - exact;
+        }
+        add_rounding(child, times, rounding);
+    }
+    if excess.abs() > 1e-9 {
+        let label = branch.chem.short_text();
+        match rounding.iter_mut().find(|(node, _)| *node == label) {
+            Some((_, total)) => *total += excess * times as f64,
+            None => rounding.push((label, excess * times as f64))
+        }
+    }
+}
@@ -0,0 +1,222 @@
+use super::{Action, ChemState, ChemTreeBranch, Optimizer, Reservoir, compress_state, compute_step, mix_leaf, ranked_leaves};
+use crate::PlanError;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+/// Build every branch left in `tree`, returning the (1-indexed) reservoir holding the product.
+///
+/// The greedy planner is tried first. If it runs out of reservoirs, the orders the branches can be
+/// mixed in are searched for one that fits. Which reservoirs are in use only depends on which
+/// branches have been built, so orders that share a dead end are only explored once. If nothing
+/// fits, the machine is compacted with `compress_state` and the batch planned again. Only when
+/// that frees nothing is the search exhaustive, and the error reports the fewest reservoirs any
+/// order needs.
+pub fn build_tree(state:&mut ChemState, tree:&mut ChemTreeBranch, actions:&mut Vec<Action>, mix_reservoirs:&[usize]) -> Result<u32, PlanError> {
+    let mut greedy_state = state.clone();
+    let mut greedy_tree = tree.clone();
+    let mut greedy_actions = actions.clone();
+    match build_greedy(&mut greedy_state, &mut greedy_tree, &mut greedy_actions, mix_reservoirs) {
+        Ok(output) => {
+            *state = greedy_state;
+            *tree = greedy_tree;
+            *actions = greedy_actions;
+            return Ok(output);
+        },
+        Err(PlanError::NoEmptyReservoir{..}) => {},
+        Err(err) => return Err(err)
+    }
+
+    let mut order = vec![];
+    if search_order(state, tree, mix_reservoirs, &mut vec![], &mut order, &mut BTreeSet::new())? {
+        let mut output = 0;
+        for id in &order {
+            let picked = find_leaf(tree, *id);
+            output = mix_leaf(state, tree, actions, mix_reservoirs, &picked)?;
+        }
+        return Ok(output);
+    }
+    let mut compact_state = state.clone();
+    let mut compact_actions = actions.clone();
+    if compress_state(&mut compact_state, mix_reservoirs, &mut compact_actions)? {
+        let output = build_tree(&mut compact_state, tree, &mut compact_actions, mix_reservoirs)?;
+        *state = compact_state;
+        *actions = compact_actions;
+        return Ok(output);
+    }
+    return Err(PlanError::NotEnoughReservoirs{
+        node:tree.chem.as_text(),
+        needed:min_reservoirs(state, tree, mix_reservoirs)?,
+        available:state.profile.reservoirs
+    });
+}
+
+fn build_greedy(state:&mut ChemState, tree:&mut ChemTreeBranch, actions:&mut Vec<Action>, mix_reservoirs:&[usize]) -> Result<u32, PlanError> {
+    while !tree.children.is_empty() {
+        compute_step(state, tree, actions, mix_reservoirs)?;
+    }
+    return compute_step(state, tree, actions, mix_reservoirs); // final mix step
+}
+
+fn find_leaf(tree:&ChemTreeBranch, id:u32) -> ChemTreeBranch {
+    return tree.get_leaves().into_iter().find(|x| x.id == id).unwrap();
+}
+
+/// Depth first search for an order that fits, trying leaves in the greedy planner's order.
+/// `built` holds the ids mixed so far, and `dead_ends` the sets of built ids known not to finish.
+fn search_order(state:&ChemState, tree:&ChemTreeBranch, mix_reservoirs:&[usize], built:&mut Vec<u32>, order:&mut Vec<u32>, dead_ends:&mut BTreeSet<Vec<u32>>) -> Result<bool, PlanError> {
+    let mut key = built.clone();
+    key.sort_unstable();
+    if dead_ends.contains(&key) {
+        return Ok(false);
+    }
+    for leaf in ranked_leaves(tree, state) {
+        let mut trial_state = state.clone();
+        let mut trial_tree = tree.clone();
+        match mix_leaf(&mut trial_state, &mut trial_tree, &mut vec![], mix_reservoirs, &leaf) {
+            Ok(_) => {},
+            Err(PlanError::NoEmptyReservoir{..}) => continue,
+            Err(err) => return Err(err)
+        }
+        order.push(leaf.id);
+        if leaf.id == tree.id {
+            return Ok(true); // the root was mixed, so everything else already has been
+        }
+        built.push(leaf.id);
+        if search_order(&trial_state, &trial_tree, mix_reservoirs, built, order, dead_ends)? {
+            return Ok(true);
+        }
+        built.pop();
+        order.pop();
+    }
+    dead_ends.insert(key);
+    return Ok(false);
+}
+
+/// the fewest reservoirs any mixing order needs, found by planning on a machine with plenty of them
+fn min_reservoirs(state:&ChemState, tree:&ChemTreeBranch, mix_reservoirs:&[usize]) -> Result<u32, PlanError> {
+    let mut roomy_state = state.clone();
+    let mut roomy_mix = mix_reservoirs.to_vec();
+    let extra = tree.get_branch_count() as usize + 1;
+    for _ in 0..extra {
+        roomy_mix.push(roomy_state.chems.len());
+        roomy_state.chems.push(Reservoir::empty());
+    }
+    roomy_state.profile.reservoirs += extra as u32;
+    let peak = min_peak(&roomy_state, tree, &roomy_mix, &mut vec![], &mut BTreeMap::new())?;
+    return Ok(peak.max(roomy_state.count_unavailable(&roomy_mix)));
+}
+
+/// the lowest number of unavailable reservoirs the rest of the tree can be built within
+fn min_peak(state:&ChemState, tree:&ChemTreeBranch, mix_reservoirs:&[usize], built:&mut Vec<u32>, memo:&mut BTreeMap<Vec<u32>, u32>) -> Result<u32, PlanError> {
+    let mut key = built.clone();
+    key.sort_unstable();
+    if let Some(peak) = memo.get(&key) {
+        return Ok(*peak);
+    }
+    let mut best = u32::MAX;
+    for leaf in ranked_leaves(tree, state) {
+        let mut trial_state = state.clone();
+        let mut trial_tree = tree.clone();
+        mix_leaf(&mut trial_state, &mut trial_tree, &mut vec![], mix_reservoirs, &leaf)?;
+        let mut peak = trial_state.count_unavailable(mix_reservoirs);
+        if leaf.id != tree.id {
+            built.push(leaf.id);
+            peak = peak.max(min_peak(&trial_state, &trial_tree, mix_reservoirs, built, memo)?);
+            built.pop();
+        }
+        best = best.min(peak);
+    }
+    memo.insert(key, best);
+    return Ok(best);
+}
+
+/// Build every branch left in `tree` like `build_tree`, then spend up to `optimizer.limit` mixing
+/// steps searching other orders for one the optimizer scores lower. `actions` holds everything
+/// planned before this batch, and is scored along with it.
+pub fn optimize_tree(state:&mut ChemState, tree:&mut ChemTreeBranch, actions:&mut Vec<Action>, mix_reservoirs:&[usize], optimizer:&Optimizer) -> Result<u32, PlanError> {
+    let mut best_state = state.clone();
+    let mut best_tree = tree.clone();
+    let mut best_actions = actions.clone();
+    let best_output = build_tree(&mut best_state, &mut best_tree, &mut best_actions, mix_reservoirs)?;
+    let best = Candidate {
+        cost:(optimizer.cost)(&best_actions),
+        state:best_state,
+        actions:best_actions,
+        output:best_output
+    };
+    let mut search = Search {mix_reservoirs, optimizer, budget:optimizer.limit, seen:HashMap::new(), best};
+    search_cost(state, tree, actions, &mut vec![], &mut search)?;
+    // compacting first costs actions, but may open up cheaper orders
+    let mut compact_state = state.clone();
+    let mut compact_actions = actions.clone();
+    if compress_state(&mut compact_state, mix_reservoirs, &mut compact_actions)? {
+        search.seen.clear();
+        search_cost(&compact_state, tree, &compact_actions, &mut vec![], &mut search)?;
+    }
+
+    let best = search.best;
+    *state = best.state;
+    *tree = best_tree; // every order ends with the whole tree built
+    *actions = best.actions;
+    return Ok(best.output);
+}
+
+/// the cheapest complete plan found so far
+struct Candidate {
+    cost:usize,
+    state:ChemState,
+    actions:Vec<Action>,
+    output:u32
+}
+
+/// what `search_cost` carries between mixing steps
+struct Search<'a> {
+    mix_reservoirs:&'a [usize],
+    optimizer:&'a Optimizer<'a>,
+    /// mixing steps left to try
+    budget:usize,
+    /// the cheapest cost each set of built branches and reservoir contents was reached at
+    seen:HashMap<(Vec<u32>, ChemState), usize>,
+    best:Candidate
+}
+
+/// Branch and bound over mixing orders. Costs grow as actions are added (bytecode length very
+/// nearly always), so a partial plan that already costs as much as the best complete one is dropped, as is any that reaches the
+/// same built branches and reservoir contents as an earlier, cheaper one.
+fn search_cost(state:&ChemState, tree:&ChemTreeBranch, actions:&[Action], built:&mut Vec<u32>, search:&mut Search) -> Result<(), PlanError> {
+    for leaf in ranked_leaves(tree, state) {
+        if search.budget == 0 {
+            return Ok(());
+        }
+        search.budget -= 1;
+        let mut trial_state = state.clone();
+        let mut trial_tree = tree.clone();
+        let mut trial_actions = actions.to_vec();
+        let output = match mix_leaf(&mut trial_state, &mut trial_tree, &mut trial_actions, search.mix_reservoirs, &leaf) {
+            Ok(output) => output,
+            Err(PlanError::NoEmptyReservoir{..}) => continue,
+            Err(err) => return Err(err)
+        };
+        let cost = (search.optimizer.cost)(&trial_actions);
+        if cost >= search.best.cost {
+            continue;
+        }
+        if leaf.id == tree.id {
+            search.best = Candidate {cost, state:trial_state, actions:trial_actions, output};
+            continue;
+        }
+        built.push(leaf.id);
+        let mut key = built.clone();
+        key.sort_unstable();
+        let key = (key, trial_state.clone());
+        let cheaper_seen = match search.seen.get(&key) {
+            Some(seen_cost) => *seen_cost <= cost,
+            None => false
+        };
+        if !cheaper_seen {
+            search.seen.insert(key, cost);
+            search_cost(&trial_state, &trial_tree, &trial_actions, built, search)?;
+        }
+        built.pop();
+    }
+    return Ok(());
+}
@@ -0,0 +1,117 @@
+use crate::{ChemToken, Layout, MachineProfile, ParseError, PlanError};
+use crate::chemicals::Rounding;
+use crate::calculator::{self, ChemTree};
+use crate::parser;
+
+/// The outcome of one rule, for one recipe or the whole program
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub rule:&'static str,
+    pub status:Status,
+    pub message:String
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    Pass,
+    /// passes, but probably not what was meant
+    Warn,
+    Fail
+}
+
+impl Diagnostic {
+    fn pass(rule:&'static str, message:String) -> Diagnostic {
+        return Diagnostic {rule, status:Status::Pass, message};
+    }
+
+    fn warn(rule:&'static str, message:String) -> Diagnostic {
+        return Diagnostic {rule, status:Status::Warn, message};
+    }
+
+    fn fail(rule:&'static str, message:String) -> Diagnostic {
+        return Diagnostic {rule, status:Status::Fail, message};
+    }
+}
+
+/// Check that `recipes` could be made in one program without planning it. Every rule is checked
+/// even after one fails, except that a recipe which does not parse is not checked any further.
+pub fn check(recipes:&Vec<String>, layout:&Layout, profile:&MachineProfile, rounding:Rounding) -> Vec<Diagnostic> {
+    let mut diagnostics = vec![];
+    let mut products = vec![];
+    for recipe in recipes {
+        let unknown = parser::unknown_substitutes(recipe);
+        for name in &unknown {
+            diagnostics.push(Diagnostic::fail("substitutes", format!("*{} in {} does not exist", name, recipe)));
+        }
+        match parser::parse(recipe.clone()) {
+            Ok(product) => {
+                diagnostics.push(Diagnostic::pass("parse", recipe.clone()));
+                products.push(product);
+            },
+            // already reported by the substitutes rule
+            Err(ParseError::UnknownSubstitute{..}) if !unknown.is_empty() => {},
+            Err(err) => diagnostics.push(Diagnostic::fail("parse", format!("{}: {}", recipe, err)))
+        }
+    }
+    for (token, _) in &products {
+        let mut token = token.clone();
+        token.set_concrete_quantity(0, rounding);
+        let mut errors = vec![];
+        let mut warnings = vec![];
+        calculator::check_fractions(&token, &mut errors, &mut warnings);
+        if errors.is_empty() {
+            diagnostics.push(Diagnostic::pass("fractions", format!("the $ parts of {} fit in their groups", token.as_text())));
+        }
+        for err in errors {
+            diagnostics.push(Diagnostic::fail("fractions", err.to_string()));
+        }
+        for warning in warnings {
+            diagnostics.push(Diagnostic::warn("fractions", warning.to_string()));
+        }
+        check_temps(&token, profile, &mut diagnostics);
+        check_beakers(&token, profile, &mut diagnostics);
+    }
+    if products.is_empty() {
+        return diagnostics;
+    }
+
+    match calculator::compute_initial_state(&calculator::base_usage(&products, rounding), layout, profile) {
+        Ok(state) => diagnostics.push(Diagnostic::pass("reservoirs", format!("{} base reagents fit in {} reservoirs", state.count_nonempty(), profile.reservoirs))),
+        Err(err) => diagnostics.push(Diagnostic::fail("reservoirs", err.to_string()))
+    }
+    match ChemTree::deconstruct(&products, layout, profile, rounding) {
+        Ok(_) => diagnostics.push(Diagnostic::pass("tree", "every recipe breaks down into mixes".to_string())),
+        // already reported by the fraction and reservoir rules
+        Err(PlanError::OverSubscribed{..}) | Err(PlanError::TooManyChemicals{..}) | Err(PlanError::LayoutOutOfRange{..}) | Err(PlanError::LayoutPinnedTwice{..})
+            | Err(PlanError::LayoutSlotConflict{..}) | Err(PlanError::LayoutFull{..}) => {},
+        Err(err) => diagnostics.push(Diagnostic::fail("tree", err.to_string()))
+    }
+    return diagnostics;
+}
+
+fn check_temps(token:&ChemToken, profile:&MachineProfile, diagnostics:&mut Vec<Diagnostic>) {
+    let temps = token.chemical.temp.iter().chain(token.chemical.stages.iter().map(|(_, temp)| temp));
+    for temp in temps {
+        if *temp < profile.min_temp || *temp > profile.max_temp {
+            diagnostics.push(Diagnostic::fail("temperatures", format!("{} heats to {}K, outside {}K to {}K", token.as_text(), temp, profile.min_temp, profile.max_temp)));
+        }
+    }
+    for chem in &token.chemical.chemicals {
+        check_temps(chem, profile, diagnostics);
+    }
+}
+
+/// A mix bigger than the largest beaker only warns, since `calc` splits it into parts that fit.
+/// A recipe that cannot be split that way fails the tree rule.
+fn check_beakers(token:&ChemToken, profile:&MachineProfile, diagnostics:&mut Vec<Diagnostic>) {
+    if token.chemical.chemicals.is_empty() {
+        return;
+    }
+    let size = token.combine_size();
+    if size > profile.largest_beaker() {
+        diagnostics.push(Diagnostic::warn("beakers", format!("{} mixes {}u, more than the largest beaker ({}u), so it is split into parts", token.as_text(), size, profile.largest_beaker())));
+    }
+    for chem in &token.chemical.chemicals {
+        check_beakers(chem, profile, diagnostics);
+    }
+}
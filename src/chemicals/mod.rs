@@ -1,18 +1,57 @@
-#[derive(Debug, Clone, Default, Hash, Eq)]
+use crate::{ParseError, PlanError};
+use std::cmp::Ordering;
+use std::hash::{Hash, Hasher};
+
+#[derive(Debug, Clone, Default, Eq)]
 pub struct ChemToken {
     pub quantity:NumberToken,
     pub chemical:Chemical,
+    /// "!n;", breaks ties in both the addition order and which subtree is built next
     pub priority:u32,
-    pub concrete_quantity:Option<u32>
+    pub concrete_quantity:Option<u32>,
+    /// where it is added relative to the rest of its group
+    pub order:Vec<AddOrder>,
+    /// when its subtree is built relative to the rest of the recipe, if it says
+    pub build:Option<BuildOrder>,
+    /// where it was written in the recipe
+    pub source:Source
+}
+
+/// Where a token was written in its recipe
+#[derive(Debug, Clone, Default, Hash, PartialEq, Eq)]
+pub struct Source {
+    /// the characters it spans, from its quantity to the end of its markers. Everything a
+    /// substitute expands to spans the "*NAME;" it was written as.
+    pub span:Option<(u32, u32)>,
+    /// the innermost substitute it is, or was expanded from
+    pub substitute:Option<String>
+}
+
+/// An addition order constraint, "!first;", "!last;" or "!after:<ingredient>;"
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AddOrder {
+    First,
+    Last,
+    After(String)
+}
+
+/// A build order hint for a subtree, "!build:first;" or "!build:last;". Ordered so that the
+/// subtrees to build soonest compare greatest.
+#[derive(Debug, Clone, Copy, Default, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub enum BuildOrder {
+    Last,
+    #[default]
+    Any,
+    First
 }
 
-#[derive(Debug, Clone, Hash, PartialEq, Eq)]
+#[derive(Debug, Clone, Hash, PartialEq, Eq, PartialOrd, Ord)]
 pub enum NumberToken {
     Constant(u32),
     Calculated(NumberOperator)
 }
 
-#[derive(Debug, Clone, Hash, PartialEq, Eq)]
+#[derive(Debug, Clone, Hash, PartialEq, Eq, PartialOrd, Ord)]
 pub struct NumberOperator {
     numerator:u32,
     denominator:u32
@@ -24,34 +63,148 @@ impl NumberOperator {
     }
 }
 
-pub fn div_up(a: u32, b: u32) -> u32 {
-    (a + (b - 1))/b
+pub fn gcd(a:u32, b:u32) -> u32 {
+    if b == 0 {
+        return a;
+    }
+    return gcd(b, a % b);
+}
+
+/// The fixed point quantities are counted in, as decimal places of a unit. Every quantity is a
+/// whole number of steps of this size, so at 2 decimals 1050 is 10.50u. Whole units by default.
+#[derive(Debug, Clone, Copy, Default, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub struct FixedPoint {
+    pub decimals:u32
 }
 
-impl NumberToken {
-    fn calculate(&self, parent_value:Option<u32>) -> u32 {
-        match self {
-            NumberToken::Constant (val) => {
-                return *val;
-            },
-            NumberToken::Calculated (operator) => {
-                let parent_value = parent_value.unwrap();
-                return div_up(parent_value*operator.numerator, operator.denominator);
+impl FixedPoint {
+    pub fn new(decimals:u32) -> FixedPoint {
+        return FixedPoint {decimals};
+    }
+
+    /// steps in a unit
+    pub fn scale(&self) -> u32 {
+        return 10u32.pow(self.decimals);
+    }
+
+    /// `amount` steps as units, ie "10.50"
+    pub fn text(&self, amount:u32) -> String {
+        if self.decimals == 0 {
+            return amount.to_string();
+        }
+        return format!("{}.{:0width$}", amount / self.scale(), amount % self.scale(), width = self.decimals as usize);
+    }
+
+    /// `amount` steps in units, for sums of rounding that are not whole steps
+    pub fn units(&self, amount:f64) -> f64 {
+        return amount / self.scale() as f64;
+    }
+
+    /// `amount` steps of this fixed point in steps of `other`, rounded to the nearest
+    pub fn convert(&self, amount:u32, other:FixedPoint) -> u32 {
+        if other.decimals >= self.decimals {
+            return amount * 10u32.pow(other.decimals - self.decimals);
+        }
+        let divisor = 10u32.pow(self.decimals - other.decimals);
+        return (amount + divisor / 2) / divisor;
+    }
+}
+
+/// How `$` fractions of a parent are rounded to whole units, or whole steps of a `FixedPoint`
+#[derive(Debug, Clone, Copy, Default, Hash, PartialEq, Eq)]
+pub enum Rounding {
+    #[default]
+    Up,
+    Down,
+    Nearest,
+    /// fractions have to come out whole, see `ChemToken::inexact_part`
+    Exact
+}
+
+impl std::str::FromStr for Rounding {
+    type Err = String;
+
+    fn from_str(string:&str) -> Result<Rounding, String> {
+        match string {
+            "up" => Ok(Rounding::Up),
+            "down" => Ok(Rounding::Down),
+            "nearest" => Ok(Rounding::Nearest),
+            "exact" => Ok(Rounding::Exact),
+            _ => Err(format!("unknown rounding {}, expected up, down, nearest or exact", string))
+        }
+    }
+}
+
+/// The concrete quantities of siblings in a parent of `parent` units. Fractions are rounded as
+/// `rounding` says, except when they are all fractions adding up to exactly the parent: then each
+/// is rounded down and the units left over go to the largest remainders, ties to the first
+/// written, so the siblings add up to the parent too. The shares have to fit in a u32, which
+/// `ChemToken::check_size` makes sure of for every parsed recipe.
+pub fn shares(parent:u32, quantities:&[&NumberToken], rounding:Rounding) -> Vec<u32> {
+    let exact:Vec<(u32, u32)> = quantities.iter().map(|quantity| match quantity {
+        NumberToken::Constant(val) => (*val, 0),
+        NumberToken::Calculated(operator) => {
+            let scaled = parent as u64 * operator.numerator as u64;
+            ((scaled / operator.denominator as u64) as u32, (scaled % operator.denominator as u64) as u32)
+        }
+    }).collect();
+    let fractions:Vec<(u32, u32)> = quantities.iter().filter_map(|x| x.fraction()).collect();
+    let whole = fractions.len() == quantities.len() && !quantities.is_empty() && {
+        let sum = ChemToken::sum_fractions(&fractions).unwrap();
+        sum.0 == sum.1
+    };
+    if whole {
+        let mut shares:Vec<u32> = exact.iter().map(|(floor, _)| *floor).collect();
+        let mut left = parent - shares.iter().sum::<u32>();
+        // compare remainders across denominators as fractions of a unit
+        let mut order:Vec<usize> = (0..shares.len()).collect();
+        order.sort_by(|a, b| {
+            let (ra, da) = (exact[*a].1 as u64, fractions[*a].1 as u64);
+            let (rb, db) = (exact[*b].1 as u64, fractions[*b].1 as u64);
+            (rb * da).cmp(&(ra * db)).then(a.cmp(b))
+        });
+        for i in order {
+            if left == 0 || exact[i].1 == 0 {
+                break;
             }
+            shares[i] += 1;
+            left -= 1;
         }
+        return shares;
     }
-    
+    return quantities.iter().zip(exact).map(|(quantity, (floor, remainder))| {
+        let denominator = quantity.fraction().map(|(_, denominator)| denominator).unwrap_or(1);
+        match rounding {
+            Rounding::Up if remainder > 0 => floor + 1,
+            Rounding::Nearest if remainder * 2 >= denominator => floor + 1,
+            _ => floor
+        }
+    }).collect();
+}
+
+impl NumberToken {
     pub fn as_text(&self) -> String {
         match self {
             NumberToken::Constant(val) => {
                 return format!("{}", val);
             },
             NumberToken::Calculated(operator) => {
+                if operator.numerator == 1 {
+                    return format!("$/{}", operator.denominator);
+                }
                 return format!("${}/{}", operator.numerator, operator.denominator);
             }
         }
     }
 
+    /// numerator and denominator of a `$` fraction
+    pub fn fraction(&self) -> Option<(u32, u32)> {
+        match self {
+            NumberToken::Calculated(operator) => Some((operator.numerator, operator.denominator)),
+            NumberToken::Constant(_) => None
+        }
+    }
+
     pub fn is_constant(&self) -> bool {
         match self {
             NumberToken::Constant (_) => {return true}
@@ -79,35 +232,138 @@ impl ChemToken {
     //     self.chemical.chemicals.push(other.clone());
     // }
 
-    pub fn combine (&mut self, other:&ChemToken) {
-        if !self.combinable(other) {
-            panic!();
+    /// the token back in recipe syntax, ie "$/2:(50:hydrogen;$/3:nitrogen;)@374;"
+    pub fn as_text(&self) -> String {
+        let body = match &self.chemical.name {
+            Some(name) => format!("{};", name),
+            None => {
+                let inner = self.chemical.inner_text(|x| x.as_text());
+                match self.chemical.temp {
+                    Some(temp) => format!("({})@{};", inner, temp),
+                    None => format!("({})", inner)
+                }
+            }
+        };
+        return format!("{}:{}", self.quantity.as_text(), body);
+    }
+
+    /// the chemical without quantities, with groups inside it collapsed, ie "(weldingfuel;(...);)@374"
+    pub fn short_text(&self) -> String {
+        if let Some(name) = &self.chemical.name {
+            return name.clone();
         }
-        match &self.quantity {
-            NumberToken::Constant(val) => {
-                match other.quantity {
-                    NumberToken::Calculated(_) => {
-                        panic!("tried to add calculated to constant");
+        if let Some(substitute) = &self.chemical.substitute {
+            return format!("*{}", substitute);
+        }
+        let inner = self.chemical.inner_text(|x| match &x.chemical.name {
+            Some(name) => format!("{};", name),
+            None => "(...);".to_string()
+        });
+        match self.chemical.temp {
+            Some(temp) => format!("({})@{}", inner, temp),
+            None => format!("({})", inner)
+        }
+    }
+
+    /// The `$` fractions among the ingredients added up, as a reduced numerator and denominator,
+    /// or `None` if none of them is a fraction
+    pub fn fraction_sum(&self) -> Option<(u32, u32)> {
+        let fractions:Vec<(u32, u32)> = self.chemical.chemicals.iter().filter_map(|x| x.quantity.fraction()).collect();
+        return ChemToken::sum_fractions(&fractions);
+    }
+
+    fn sum_fractions(fractions:&[(u32, u32)]) -> Option<(u32, u32)> {
+        let mut sum:Option<(u32, u32)> = None;
+        for &(numerator, denominator) in fractions {
+            let (sum_numerator, sum_denominator) = sum.unwrap_or((0, 1));
+            let common = sum_denominator / gcd(sum_denominator, denominator) * denominator;
+            let total = sum_numerator * (common / sum_denominator) + numerator * (common / denominator);
+            let divisor = gcd(total, common);
+            sum = Some((total / divisor, common / divisor));
+        }
+        return sum;
+    }
+
+    /// how ordering constraints refer to it, its name or "*" and the substitute it came from
+    pub fn order_name(&self) -> String {
+        if let Some(name) = &self.chemical.name {
+            return name.clone();
+        }
+        if let Some(substitute) = &self.chemical.substitute {
+            return format!("*{}", substitute);
+        }
+        return self.short_text();
+    }
+
+    fn answers_to(&self, name:&str) -> bool {
+        let name = name.trim_start_matches('*');
+        let own = self.chemical.name.as_ref().or(self.chemical.substitute.as_ref());
+        return own.is_some_and(|own| own.eq_ignore_ascii_case(name));
+    }
+
+    /// The order to add this group's ingredients in, as indexes into them. Heat stages come
+    /// first, then the ordering constraints, and ties go to the highest priority and then the
+    /// order they are written in.
+    pub fn addition_order(&self) -> Result<Vec<usize>, PlanError> {
+        let chems = &self.chemical.chemicals;
+        let before = self.precedence()?;
+        let mut order = vec![];
+        let mut remaining:Vec<usize> = (0..chems.len()).collect();
+        while !remaining.is_empty() {
+            let next = remaining.iter().copied()
+                .filter(|i| !remaining.iter().any(|j| before[*j][*i]))
+                .min_by_key(|i| (std::cmp::Reverse(chems[*i].priority), *i));
+            let next = match next {
+                Some(next) => next,
+                None => return Err(PlanError::ContradictoryOrder{
+                    node:self.as_text(),
+                    ingredients:remaining.iter().map(|i| chems[*i].order_name()).collect::<Vec<String>>().join(", ")
+                })
+            };
+            remaining.retain(|i| *i != next);
+            order.push(next);
+        }
+        return Ok(order);
+    }
+
+    /// Which of this group's ingredients may go in before all the others, as the heat stages
+    /// and ordering constraints allow. Priority only breaks ties, so it does not count here.
+    pub fn may_go_first(&self) -> Result<Vec<bool>, PlanError> {
+        let before = self.precedence()?;
+        return Ok((0..before.len()).map(|i| (0..before.len()).all(|j| !before[j][i])).collect());
+    }
+
+    /// before[i][j] when ingredient i has to go in before ingredient j
+    fn precedence(&self) -> Result<Vec<Vec<bool>>, PlanError> {
+        let chems = &self.chemical.chemicals;
+        let count = chems.len();
+        let mut before:Vec<Vec<bool>> = (0..count).map(|i| (0..count).map(|j| self.chemical.stage_of(i) < self.chemical.stage_of(j)).collect()).collect();
+        for (i, chem) in chems.iter().enumerate() {
+            for order in &chem.order {
+                match order {
+                    AddOrder::First => {
+                        for j in (0..count).filter(|j| *j != i && !chems[*j].order.contains(&AddOrder::First)) {
+                            before[i][j] = true;
+                        }
                     },
-                    NumberToken::Constant(other_val) => {
-                        self.quantity = NumberToken::Constant(val+other_val);
-                    }
-                }
-            },
-            NumberToken::Calculated(operator) => {
-                match &other.quantity {
-                    NumberToken::Calculated(other_operator) => {
-                        assert_eq!(other_operator.denominator, operator.denominator);
-                        let new_numerator = operator.numerator + other_operator.numerator;
-                        self.quantity = NumberToken::Calculated(NumberOperator{numerator:new_numerator, denominator:operator.denominator});
+                    AddOrder::Last => {
+                        for j in (0..count).filter(|j| *j != i && !chems[*j].order.contains(&AddOrder::Last)) {
+                            before[j][i] = true;
+                        }
                     },
-                    NumberToken::Constant(_) => {
-                        panic!("tried to add constant to calculated");
+                    AddOrder::After(name) => {
+                        let earlier:Vec<usize> = (0..count).filter(|j| chems[*j].answers_to(name)).collect();
+                        if earlier.is_empty() {
+                            return Err(PlanError::UnknownOrderReference{node:self.as_text(), name:name.clone()});
+                        }
+                        for j in earlier {
+                            before[j][i] = true;
+                        }
                     }
-                    
                 }
             }
         }
+        return Ok(before);
     }
 
     pub fn combine_size(&self) -> u32 {
@@ -126,22 +382,119 @@ impl ChemToken {
         return self.concrete_quantity.unwrap();
     }
 
-    pub fn combinable (&self, other:&ChemToken) -> bool{
-        return self.chemical == other.chemical && self.quantity.is_constant() == other.quantity.is_constant();
+    /// Fail if this token or anything inside could come to more than a u32 of units, or its parts
+    /// add up to more, with `$` parts rounded up. The concrete quantities and sizes are u32s.
+    pub fn check_size(&self) -> Result<(), ParseError> {
+        return self.check_size_in(0).map(|_| ());
     }
 
-    pub fn set_concrete_quantity(&mut self, parent_quantity:u32) {
-        match &self.quantity {
-            NumberToken::Constant(val)=>  {
-                self.concrete_quantity = Some(*val);
-            },
-            NumberToken::Calculated(_) => {
-                self.concrete_quantity = Some(self.quantity.calculate(Some(parent_quantity)));
+    /// the most this token can come to in a parent of `parent` units
+    fn check_size_in(&self, parent:u64) -> Result<u64, ParseError> {
+        let size = match &self.quantity {
+            NumberToken::Constant(val) => *val as u64,
+            NumberToken::Calculated(operator) => (parent * operator.numerator as u64).div_ceil(operator.denominator as u64)
+        };
+        let mut parts = 0;
+        for chem in &self.chemical.chemicals {
+            parts += chem.check_size_in(size)?;
+        }
+        // parts making up the whole group are shared out to add up to it exactly
+        let whole = self.chemical.chemicals.iter().all(|x| x.quantity.fraction().is_some());
+        if whole && matches!(self.fraction_sum(), Some((numerator, denominator)) if numerator == denominator) {
+            parts = size;
+        }
+        if size.max(parts) > u32::MAX as u64 {
+            return Err(self.too_large());
+        }
+        return Ok(size);
+    }
+
+    fn too_large(&self) -> ParseError {
+        let position = self.source.span.map(|(start, _)| start).unwrap_or(0);
+        return ParseError::TooLarge{position, node:self.as_text()};
+    }
+
+    pub fn set_concrete_quantity(&mut self, parent_quantity:u32, rounding:Rounding) {
+        self.concrete_quantity = Some(shares(parent_quantity, &[&self.quantity], rounding)[0]);
+        self.concretize_chemicals(rounding);
+    }
+
+    /// set the concrete quantities of everything inside, from this token's own
+    pub fn concretize_chemicals(&mut self, rounding:Rounding) {
+        let quantities:Vec<&NumberToken> = self.chemical.chemicals.iter().map(|x| &x.quantity).collect();
+        let shares = shares(self.size(), &quantities, rounding);
+        for (chem, share) in self.chemical.chemicals.iter_mut().zip(shares) {
+            chem.concrete_quantity = Some(share);
+            chem.concretize_chemicals(rounding);
+        }
+    }
+
+    /// the first `$` part inside that is not a whole number of units, as the part and its parent
+    pub fn inexact_part(&self) -> Option<(&ChemToken, &ChemToken)> {
+        for chem in &self.chemical.chemicals {
+            if let Some((numerator, denominator)) = chem.quantity.fraction() {
+                if !(self.size() as u64 * numerator as u64).is_multiple_of(denominator as u64) {
+                    return Some((chem, self));
+                }
+            }
+            if let Some(part) = chem.inexact_part() {
+                return Some(part);
+            }
+        }
+        return None;
+    }
+
+    /// The concrete quantities inside, one per line and indented by depth, with every `$` part
+    /// that had to be rounded shown against its exact share, in units of `point`
+    pub fn expanded_text(&self, depth:usize, point:FixedPoint) -> String {
+        let name = match &self.chemical.name {
+            Some(name) => name.clone(),
+            None => {
+                let mut label = match &self.chemical.substitute {
+                    Some(substitute) => format!("*{}", substitute),
+                    None => "group".to_string()
+                };
+                if let Some(temp) = self.chemical.temp {
+                    label = format!("{} @{}K", label, temp);
+                }
+                label
+            }
+        };
+        let mut text = format!("{}{}u {}", "  ".repeat(depth), point.text(self.size()), name);
+        text.push('\n');
+        for chem in &self.chemical.chemicals {
+            let mut line = chem.expanded_text(depth + 1, point);
+            if let Some((numerator, denominator)) = chem.quantity.fraction() {
+                let exact = self.size() as f64 * numerator as f64 / denominator as f64;
+                if chem.size() as f64 != exact {
+                    let first = line.find('\n').unwrap();
+                    line.insert_str(first, &format!(" ({} of {}u is {:.2}u)", chem.quantity.as_text(), point.text(self.size()), point.units(exact)));
+                }
+            }
+            text.push_str(&line);
+        }
+        return text;
+    }
+
+    /// Multiply every plain quantity inside by `factor`, ie to count them in a finer fixed point.
+    /// `$` fractions are left alone, so concrete quantities have to be set again afterwards.
+    /// Fails if a quantity no longer fits, as `check_size` does.
+    pub fn scale_quantities(&mut self, factor:u32) -> Result<(), ParseError> {
+        self.scale_constants(factor)?;
+        return self.check_size();
+    }
+
+    fn scale_constants(&mut self, factor:u32) -> Result<(), ParseError> {
+        if let NumberToken::Constant(val) = self.quantity {
+            match val.checked_mul(factor) {
+                Some(scaled) => self.quantity = NumberToken::Constant(scaled),
+                None => return Err(self.too_large())
             }
         }
         for chem in &mut self.chemical.chemicals {
-            chem.set_concrete_quantity(self.concrete_quantity.unwrap());
+            chem.scale_constants(factor)?;
         }
+        return Ok(());
     }
 
     /// for error checking, if modifying the concrete value unset the childrens' concrete values
@@ -152,16 +505,39 @@ impl ChemToken {
     }
 }
 
-#[derive(Debug, Clone, Default, Hash, Eq)]
+#[derive(Debug, Clone, Default, Eq)]
 pub struct Chemical {
     pub name:Option<String>,
     pub chemicals:Vec<ChemToken>,
-    pub temp:Option<u32>
+    pub temp:Option<u32>,
+    /// the substitute it was written as, ie "OIL" for "*OIL;"
+    pub substitute:Option<String>,
+    /// heats partway through the group, as (ingredients added before it, temp)
+    pub stages:Vec<(usize, u32)>
+}
+
+impl Chemical {
+    /// which heat stage the ingredient at `index` is added in, 0 before the first heat
+    pub fn stage_of(&self, index:usize) -> usize {
+        return self.stages.iter().filter(|(added, _)| *added <= index).count();
+    }
+
+    /// the group's ingredients in recipe syntax, with any heats between them
+    fn inner_text(&self, ingredient:impl Fn(&ChemToken) -> String) -> String {
+        let mut text = String::new();
+        for (i, chem) in self.chemicals.iter().enumerate() {
+            text.push_str(&ingredient(chem));
+            for (_, temp) in self.stages.iter().filter(|(added, _)| *added == i + 1) {
+                text.push_str(&format!("@{};", temp));
+            }
+        }
+        return text;
+    }
 }
 
 impl PartialEq for Chemical {
     fn eq(&self, other: &Self) -> bool {
-        self.name == other.name && self.chemicals == other.chemicals
+        self.name == other.name && self.chemicals == other.chemicals && self.stages == other.stages
     }
 }
 
@@ -169,4 +545,45 @@ impl PartialEq for ChemToken {
     fn eq(&self, other: &Self) -> bool {
         self.quantity == other.quantity && self.chemical == other.chemical
     }
-}
\ No newline at end of file
+}
+
+// hashes must agree with the equality above, which ignores final temps, substitute names, priorities, orders and concrete quantities
+impl Hash for Chemical {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.name.hash(state);
+        self.chemicals.hash(state);
+        self.stages.hash(state);
+    }
+}
+
+impl Hash for ChemToken {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.quantity.hash(state);
+        self.chemical.hash(state);
+    }
+}
+
+// ordered the same way, so chemicals can key ordered maps
+impl Ord for Chemical {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.name.cmp(&other.name).then_with(|| self.chemicals.cmp(&other.chemicals)).then_with(|| self.stages.cmp(&other.stages))
+    }
+}
+
+impl PartialOrd for Chemical {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for ChemToken {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.quantity.cmp(&other.quantity).then_with(|| self.chemical.cmp(&other.chemical))
+    }
+}
+
+impl PartialOrd for ChemToken {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
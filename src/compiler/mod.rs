@@ -1,7 +1,10 @@
-use crate::Action;
-use std::collections::HashMap;
+use crate::{Action, CompileError, CompileWarning, MachineProfile, Provenance, Step};
+use crate::chemicals::FixedPoint;
+use std::collections::BTreeMap;
 use structopt::StructOpt;
 
+mod tape;
+
 #[derive(Debug, Clone, Hash, PartialEq, Eq)]
 pub enum Command {
     CursorRight(u32),
@@ -10,36 +13,62 @@ pub enum Command {
     Subtract(u32),
     Get,
     ToSx,
+    // the language has these, but no step needs them
+    #[allow(dead_code)]
     FromSx,
     ToTx,
+    #[allow(dead_code)]
     FromTx,
     ToAx,
     FromAx,
     Heat,
     Transfer,
+    LoopStart,
+    LoopEnd,
     NoOp
 }
 
-#[derive(StructOpt, Debug)]
+/// A command with what it was compiled for, and the part of the recipe that was planned for.
+/// The constants written before the first action have no provenance.
+#[derive(Debug, Clone, Hash, PartialEq, Eq)]
+pub struct Emitted {
+    pub command:Command,
+    pub part:Part,
+    pub provenance:Provenance
+}
+
+/// What a stretch of the program does
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq)]
+pub enum Part {
+    /// writes `value` to tape cell `cell` before the first action
+    Constant{cell:u32, value:u32},
+    /// runs the action at this index
+    Action(usize),
+    /// the `~` that ends the program
+    End
+}
+
+#[derive(StructOpt, Debug, Default)]
 pub struct CompilerFlags {
     #[structopt(short, long)]
-    sideproduct_pills:bool
+    pub sideproduct_pills:bool
 }
 
 const ZERO:u32 = 0;
-const MAKE_PILL:u32 = 11;
-const MAKE_VIAL:u32 = 12;
-const EJECT:u32 = 13;
-const ALL:u32 = 100;
 
 /// constants that will appear even if they don't have a reference in an action
-static FORCED_CONSTANTS: &'static [u32] = &[ZERO, MAKE_PILL, MAKE_VIAL, EJECT, ALL];
+fn forced_constants(profile:&MachineProfile) -> Vec<u32> {
+    return vec![ZERO, profile.pill_target, profile.vial_target, profile.eject_target, profile.transfer_all()];
+}
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 struct ProgramState {
     pointer_position:u32,
-    constants:HashMap<u32,u32>,
-    scratch:u32
+    constants:BTreeMap<u32,u32>,
+    scratch:u32,
+    /// index of the action being compiled, for error reporting
+    action:usize,
+    profile:MachineProfile
 }
 
 impl ProgramState {
@@ -63,47 +92,164 @@ impl ProgramState {
         }
     }
 
-    fn goto_constant(&mut self, constant:u32) -> Command {
+    /// The commands adding `value` to the cell under the cursor. Either `value` adds, or
+    /// whichever loop is shortest: `counter` is added to the empty cell to the right and counted
+    /// down, adding `times` each time round. What is left over is added before the loop, which
+    /// leaves the cursor on the emptied counter, or taken away after it.
+    fn write_constant(&mut self, value:u32) -> Vec<Command> {
+        let mut best = None;
+        let mut best_length = value;
+        // a counter above the square root is the same loop as a smaller one with more times round
+        for counter in 2..=value.isqrt() + 1 {
+            for times in [value / counter, value.div_ceil(counter)] {
+                let product = counter * times;
+                // the brackets and moves take 5 characters, and taking away after one more move back
+                let length = counter + times + product.abs_diff(value) + if product > value {6} else {5};
+                if times > 0 && length < best_length {
+                    best = Some((counter, times));
+                    best_length = length;
+                }
+            }
+        }
+        let (counter, times) = match best {
+            Some(best) => best,
+            None => return vec![Command::Add(value)]
+        };
+        let product = counter * times;
+        let mut commands = vec![];
+        if product < value {
+            commands.push(Command::Add(value - product));
+        }
+        commands.push(self.move_cursor_right(1));
+        commands.extend([Command::Add(counter), Command::LoopStart, Command::CursorLeft(1), Command::Add(times), Command::CursorRight(1), Command::Subtract(1), Command::LoopEnd]);
+        if product > value {
+            commands.push(self.move_cursor_left(1));
+            commands.push(Command::Subtract(product - value));
+        }
+        return commands;
+    }
+
+    fn goto_constant(&mut self, constant:u32) -> Result<Command, CompileError> {
         if !self.constants.contains_key(&constant) {
-            panic!("MISSING: {}", constant);
+            return Err(CompileError::MissingConstant{constant, action:self.action});
         }
         let register = *self.constants.get(&constant).unwrap();
-        return self.goto_register(register);
+        return Ok(self.goto_register(register));
     }
 }
 
-pub fn compile (actions:&Vec<Action>, flags:&CompilerFlags) -> Vec<Command> {
-    let (constants, scratch) = extract_constants(&actions);
+pub fn compile (actions:&Vec<Action>, flags:&CompilerFlags, profile:&MachineProfile) -> Result<Vec<Emitted>, CompileError> {
+    let (constants, scratch) = extract_constants(actions, profile);
     let pointer_position = 0;
-    let mut state = ProgramState{constants, pointer_position, scratch:scratch};
-    let mut commands = vec![];
-    create_constants(&mut state, &mut commands);
-    for action in actions {
-        create_commands_from_action(&mut commands, &mut state, action, &flags);
+    let mut state = ProgramState{constants, pointer_position, scratch, action:0, profile:profile.clone()};
+    let mut emitted = vec![];
+    create_constants(&mut state, &mut emitted)?;
+    for (i, action) in actions.iter().enumerate() {
+        state.action = i;
+        let mut commands = vec![];
+        create_commands_from_action(&mut commands, &mut state, action, flags)?;
+        emitted.extend(commands.into_iter().map(|command| Emitted {command, part:Part::Action(i), provenance:action.provenance.clone()}));
+    }
+    return Ok(emitted);
+}
+
+/// The bytecode split into the stretches compiled for each constant and action, as each one's
+/// character offset, code and what it is for, ending with the `~`
+pub fn listing(commands:&Vec<Emitted>) -> Vec<(usize, String, Part)> {
+    let mut lines:Vec<(usize, String, Part)> = vec![];
+    let mut offset = 0;
+    for emitted in commands {
+        let code = command_code(&emitted.command);
+        match lines.last_mut() {
+            Some((_, line, part)) if *part == emitted.part => line.push_str(&code),
+            _ => lines.push((offset, code.clone(), emitted.part))
+        }
+        offset += code.len();
     }
-    return commands;
+    lines.push((offset, "~".to_string(), Part::End));
+    return lines;
 }
 
-fn create_constants (state:&mut ProgramState, commands:&mut Vec<Command>) {
-    let mut constants:Vec<u32> = state.constants.keys().map(|x| *x).collect();
+/// the provenance of the command at character `offset` of the bytecode, if the offset is in it
+pub fn locate(commands:&Vec<Emitted>, offset:usize) -> Option<&Provenance> {
+    let mut start = 0;
+    for emitted in commands {
+        start += command_code(&emitted.command).len();
+        if offset < start {
+            return Some(&emitted.provenance);
+        }
+    }
+    return None;
+}
+
+/// The actions with their volumes, counted in `planned`, converted to the fixed point the
+/// machine takes. Amounts the machine cannot take exactly are rounded to the nearest it can, with
+/// a warning for each.
+pub fn encode_amounts(actions:&[Action], planned:FixedPoint, machine:FixedPoint) -> (Vec<Action>, Vec<CompileWarning>) {
+    let mut encoded = vec![];
+    let mut warnings = vec![];
+    for (i, action) in actions.iter().enumerate() {
+        let mut convert = |amount:u32| {
+            let converted = planned.convert(amount, machine);
+            if machine.convert(converted, planned) != amount {
+                warnings.push(CompileWarning::RoundingLoss{action:i, planned:planned.text(amount), encoded:machine.text(converted)});
+            }
+            return converted;
+        };
+        let step = match action.step {
+            Step::Transfer{amount, source, target} => Step::Transfer{amount:convert(amount), source, target},
+            Step::Heat{temp, target} => Step::Heat{temp, target},
+            Step::Eject{target} => Step::Eject{target},
+            Step::DumpByproduct{target, remaining} => Step::DumpByproduct{target, remaining:convert(remaining)},
+            Step::EjectDownTo{target, amount} => Step::EjectDownTo{target, amount:convert(amount)},
+            Step::CreateBottle{target, amount} => Step::CreateBottle{target, amount:convert(amount)},
+            Step::CreatePill{target, amount} => Step::CreatePill{target, amount:convert(amount)}
+        };
+        encoded.push(Action::new(step, &action.provenance));
+    }
+    return (encoded, warnings);
+}
+
+/// Write every constant to its cell. They go in cell order, so the cell right of the one being
+/// written is still empty for `write_constant` to count a loop down in. The tape this leaves
+/// is checked by running it.
+fn create_constants (state:&mut ProgramState, commands:&mut Vec<Emitted>) -> Result<(), CompileError> {
+    let mut constants:Vec<u32> = state.constants.keys().copied().collect();
     constants.sort_by(|x1,x2| state.constants.get(x1).unwrap().cmp(state.constants.get(x2).unwrap()));
     for constant in constants {
-        commands.push(state.goto_register(*state.constants.get(&constant).unwrap()).clone());
-        commands.push(Command::Add(constant))
+        let cell = *state.constants.get(&constant).unwrap();
+        let part = Part::Constant{cell, value:constant};
+        commands.push(Emitted {command:state.goto_register(cell), part, provenance:Provenance::default()});
+        for command in state.write_constant(constant) {
+            commands.push(Emitted {command, part, provenance:Provenance::default()});
+        }
+    }
+    let prologue:Vec<Command> = commands.iter().map(|x| x.command.clone()).collect();
+    let tape = tape::run(&prologue).unwrap_or_default();
+    let cell_value = |cell:u32| tape.get(cell as usize).copied();
+    for (constant, cell) in &state.constants {
+        if cell_value(*cell) != Some(*constant) {
+            return Err(CompileError::BadConstant{constant:*constant, cell:*cell});
+        }
     }
+    // the scratch register is used as it is found
+    if cell_value(state.scratch).is_some_and(|x| x != 0) {
+        return Err(CompileError::BadConstant{constant:ZERO, cell:state.scratch});
+    }
+    return Ok(());
 }
 
-fn extract_constants (actions:&Vec<Action>) -> (HashMap<u32,u32>, u32) {
-    let mut map = HashMap::new();
+fn extract_constants (actions:&Vec<Action>, profile:&MachineProfile) -> (BTreeMap<u32,u32>, u32) {
+    let mut map = BTreeMap::new();
     let mut register_counter = 0;
     for action in actions {
-        match *action {
-            Action::Transfer{amount, source, target} => {
+        match action.step {
+            Step::Transfer{amount, source, target} => {
                 add_constant(&mut map, amount, &mut register_counter);
                 add_constant(&mut map, source, &mut register_counter);
                 add_constant(&mut map, target, &mut register_counter);
             },
-            Action::Heat{temp, target} => {
+            Step::Heat{temp, target} => {
                 if temp > 273 {
                     add_constant(&mut map, temp-273, &mut register_counter);
                 } else {
@@ -111,74 +257,73 @@ fn extract_constants (actions:&Vec<Action>) -> (HashMap<u32,u32>, u32) {
                 }
                 add_constant(&mut map, target, &mut register_counter);
             },
-            Action::Eject{target} => {
+            Step::Eject{target} => {
                 add_constant(&mut map, target, &mut register_counter);
             },
-            Action::DumpByproduct{target, remaining} => {
+            Step::DumpByproduct{target, remaining} => {
                 add_constant(&mut map, target, &mut register_counter);
                 add_constant(&mut map, remaining, &mut register_counter);
             },
-            Action::EjectDownTo{target, amount} => {
+            Step::EjectDownTo{target, amount} => {
                 add_constant(&mut map, target, &mut register_counter);
                 add_constant(&mut map, amount, &mut register_counter);
             },
-            Action::CreateBottle{target, amount} => {
+            Step::CreateBottle{target, amount} => {
                 add_constant(&mut map, target, &mut register_counter);
                 add_constant(&mut map, amount, &mut register_counter);
             },
-            Action::CreatePill{target, amount} => {
+            Step::CreatePill{target, amount} => {
                 add_constant(&mut map, target, &mut register_counter);
                 add_constant(&mut map, amount, &mut register_counter);
             }
         }
     }
-    for x in FORCED_CONSTANTS {
-        add_constant(&mut map, *x, &mut register_counter);
+    for x in forced_constants(profile) {
+        add_constant(&mut map, x, &mut register_counter);
     }
 
-    println!("{:?}", &map);
     return (map, register_counter);
 }
 
-fn create_commands_from_action (commands:&mut Vec<Command>, state:&mut ProgramState, action:&Action, flags:&CompilerFlags) {
-    match *action {
-        Action::Transfer{amount, source, target} => {
-            commands.push(state.goto_constant(amount));
+fn create_commands_from_action (commands:&mut Vec<Command>, state:&mut ProgramState, action:&Action, flags:&CompilerFlags) -> Result<(), CompileError> {
+    match action.step {
+        Step::Transfer{amount, source, target} => {
+            commands.push(state.goto_constant(amount)?);
             commands.push(Command::ToAx);
-            commands.push(state.goto_constant(source));
+            commands.push(state.goto_constant(source)?);
             commands.push(Command::ToSx);
-            commands.push(state.goto_constant(target));
+            commands.push(state.goto_constant(target)?);
             commands.push(Command::ToTx);
             commands.push(Command::Transfer);
         },
-        Action::Heat{temp, target} => {
+        Step::Heat{temp, target} => {
             if temp > 273 {
-                commands.push(state.goto_constant(temp - 273));
+                commands.push(state.goto_constant(temp - 273)?);
                 commands.push(Command::ToAx);
-                commands.push(state.goto_constant(ZERO));
+                commands.push(state.goto_constant(ZERO)?);
                 commands.push(Command::ToTx);
             } else {
-                commands.push(state.goto_constant(ZERO));
+                commands.push(state.goto_constant(ZERO)?);
                 commands.push(Command::ToAx);
-                commands.push(state.goto_constant(273-temp));
+                commands.push(state.goto_constant(273-temp)?);
                 commands.push(Command::ToTx);
 
             }
-            commands.push(state.goto_constant(target));
+            commands.push(state.goto_constant(target)?);
             commands.push(Command::ToSx);
             commands.push(Command::Heat);
         },
-        Action::Eject{target} => {
-            commands.push(state.goto_constant(target));
+        Step::Eject{target} => {
+            commands.push(state.goto_constant(target)?);
             commands.push(Command::ToSx);
-            commands.push(state.goto_constant(ALL));
+            commands.push(state.goto_constant(state.profile.transfer_all())?);
             commands.push(Command::ToAx);
-            commands.push(state.goto_constant(MAKE_PILL));
+            commands.push(state.goto_constant(state.profile.pill_target)?);
             commands.push(Command::ToTx);
             commands.push(Command::Transfer);
         },
-        Action::DumpByproduct{target, remaining} => {
-            commands.push(state.goto_constant(target));
+        Step::DumpByproduct{target, remaining} => {
+            commands.push(state.goto_constant(target)?);
             commands.push(Command::ToSx);
             commands.push(Command::Get);
             commands.push(state.goto_register(state.scratch));
@@ -186,15 +331,15 @@ fn create_commands_from_action (commands:&mut Vec<Command>, state:&mut ProgramSt
             commands.push(Command::Subtract(remaining));
             commands.push(Command::ToAx);
             if flags.sideproduct_pills {
-                commands.push(state.goto_constant(MAKE_PILL));
+                commands.push(state.goto_constant(state.profile.pill_target)?);
             } else {
-                commands.push(state.goto_constant(EJECT));
+                commands.push(state.goto_constant(state.profile.eject_target)?);
             }
             commands.push(Command::ToTx);
             commands.push(Command::Transfer);
         },
-        Action::EjectDownTo{target, amount} => {
-            commands.push(state.goto_constant(target));
+        Step::EjectDownTo{target, amount} => {
+            commands.push(state.goto_constant(target)?);
             commands.push(Command::ToSx);
             commands.push(Command::Get);
             commands.push(state.goto_register(state.scratch));
@@ -202,90 +347,137 @@ fn create_commands_from_action (commands:&mut Vec<Command>, state:&mut ProgramSt
             commands.push(Command::Subtract(amount));
             commands.push(Command::ToAx);
             if flags.sideproduct_pills {
-                commands.push(state.goto_constant(MAKE_PILL));
+                commands.push(state.goto_constant(state.profile.pill_target)?);
             } else {
-                commands.push(state.goto_constant(EJECT));
+                commands.push(state.goto_constant(state.profile.eject_target)?);
             }
             commands.push(Command::ToTx);
             commands.push(Command::Transfer);
         },
-        Action::CreateBottle{target, amount} => {
-            commands.push(state.goto_constant(target));
+        Step::CreateBottle{target, amount} => {
+            commands.push(state.goto_constant(target)?);
             commands.push(Command::ToSx);
-            commands.push(state.goto_constant(amount));
+            commands.push(state.goto_constant(amount)?);
             commands.push(Command::ToAx);
-            commands.push(state.goto_constant(MAKE_VIAL));
+            commands.push(state.goto_constant(state.profile.vial_target)?);
             commands.push(Command::ToTx);
             commands.push(Command::Transfer);
         },
-        Action::CreatePill{target, amount} => {
-            commands.push(state.goto_constant(target));
+        Step::CreatePill{target, amount} => {
+            commands.push(state.goto_constant(target)?);
             commands.push(Command::ToSx);
-            commands.push(state.goto_constant(amount));
+            commands.push(state.goto_constant(amount)?);
             commands.push(Command::ToAx);
-            commands.push(state.goto_constant(MAKE_PILL));
+            commands.push(state.goto_constant(state.profile.pill_target)?);
             commands.push(Command::ToTx);
             commands.push(Command::Transfer);
         },
     }
+    return Ok(());
 }
 
-fn add_constant(map:&mut HashMap<u32,u32>, constant:u32, counter:&mut u32) {
-    if !map.contains_key(&constant) {
-        map.insert(constant, *counter);
+fn add_constant(map:&mut BTreeMap<u32,u32>, constant:u32, counter:&mut u32) {
+    map.entry(constant).or_insert_with(|| {
         *counter += 1;
-    }
+        *counter - 1
+    });
 }
 
 
-pub fn to_bytecode (commands:&Vec<Command>) -> String {
+pub fn to_bytecode (commands:&Vec<Emitted>) -> String {
     let mut code = "".to_string();
-    for command in commands {
-        let command_code = match *command {
-            Command::CursorRight(amount) => {
-                (0..amount).map(|_| ">").collect::<String>()
-            },
-            Command::CursorLeft(amount) => {
-                (0..amount).map(|_| "<").collect::<String>()
-            },
-            Command::Add(amount) => {
-                (0..amount).map(|_| "+").collect::<String>()
-            },
-            Command::Subtract(amount) => {
-                (0..amount).map(|_| "-").collect::<String>()
-            },
-            Command::Get => {
-                ",".to_string()
-            },
-            Command::ToSx => {
-                "}".to_string()
-            },
-            Command::FromSx => {
-                "{".to_string()
-            },
-            Command::ToTx => {
-                ")".to_string()
-            },
-            Command::FromTx => {
-                "(".to_string()
-            },
-            Command::ToAx => {
-                "'".to_string()
-            },
-            Command::FromAx => {
-                "^".to_string()
-            },
-            Command::Heat => {
-                "$".to_string()
-            },
-            Command::Transfer => {
-                "@".to_string()
-            },
-            Command::NoOp => {
-                "".to_string()
-            }
-        };
-        code = format!("{}{}",code,command_code);
+    for emitted in commands {
+        code = format!("{}{}",code,command_code(&emitted.command));
     }
     return format!("{}~",code);
 }
+
+fn command_code (command:&Command) -> String {
+    match *command {
+        Command::CursorRight(amount) => {
+            (0..amount).map(|_| ">").collect::<String>()
+        },
+        Command::CursorLeft(amount) => {
+            (0..amount).map(|_| "<").collect::<String>()
+        },
+        Command::Add(amount) => {
+            (0..amount).map(|_| "+").collect::<String>()
+        },
+        Command::Subtract(amount) => {
+            (0..amount).map(|_| "-").collect::<String>()
+        },
+        Command::Get => {
+            ",".to_string()
+        },
+        Command::ToSx => {
+            "}".to_string()
+        },
+        Command::FromSx => {
+            "{".to_string()
+        },
+        Command::ToTx => {
+            ")".to_string()
+        },
+        Command::FromTx => {
+            "(".to_string()
+        },
+        Command::ToAx => {
+            "'".to_string()
+        },
+        Command::FromAx => {
+            "^".to_string()
+        },
+        Command::Heat => {
+            "$".to_string()
+        },
+        Command::Transfer => {
+            "@".to_string()
+        },
+        Command::LoopStart => {
+            "[".to_string()
+        },
+        Command::LoopEnd => {
+            "]".to_string()
+        },
+        Command::NoOp => {
+            "".to_string()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn constants_run_to_their_value() {
+        let mut subtracted = 0;
+        for value in 0..=500 {
+            let mut state = ProgramState{constants:BTreeMap::new(), pointer_position:0, scratch:0, action:0, profile:MachineProfile::default()};
+            let commands = state.write_constant(value);
+            let tape = tape::run(&commands).unwrap_or_else(|| panic!("{} does not run", value));
+            assert_eq!(tape[0], value);
+            assert!(tape[1..].iter().all(|x| *x == 0), "{} leaves the counter set", value);
+            let moved:i64 = commands.iter().map(|x| match x {
+                Command::CursorRight(amount) => *amount as i64,
+                Command::CursorLeft(amount) => -(*amount as i64),
+                _ => 0
+            }).sum();
+            assert_eq!(state.pointer_position as i64, moved, "{} loses track of the cursor", value);
+            let length:usize = commands.iter().map(|x| command_code(x).len()).sum();
+            assert!(length <= value as usize, "{} is longer than adding it", value);
+            if commands.last().is_some_and(|x| matches!(x, Command::Subtract(_))) {
+                subtracted += 1;
+            }
+        }
+        assert!(subtracted > 0, "no constant overshoots with its loop and takes away");
+    }
+
+    #[test]
+    fn tape_rejects_unbalanced_loops() {
+        assert_eq!(tape::run(&[Command::Add(3), Command::LoopStart, Command::Subtract(1), Command::LoopEnd]), Some(vec![0]));
+        assert_eq!(tape::run(&[Command::Add(1), Command::LoopStart, Command::Subtract(1)]), None);
+        assert_eq!(tape::run(&[Command::Add(1), Command::LoopEnd]), None);
+        assert_eq!(tape::run(&[Command::LoopEnd, Command::LoopStart]), None);
+    }
+}
@@ -0,0 +1,58 @@
+use super::Command;
+
+/// most commands run before a program is taken to loop forever
+const STEP_LIMIT:usize = 10_000_000;
+
+/// Run `commands` on an empty tape, returning the tape afterwards. Only the cursor, arithmetic
+/// and loop commands are followed, the rest are skipped. `None` if a cell would go below zero,
+/// the cursor off the left of the tape, a loop is unbalanced or it runs too long.
+pub fn run(commands:&[Command]) -> Option<Vec<u32>> {
+    let jumps = match_loops(commands)?;
+    let mut tape = vec![0u32];
+    let mut pointer = 0usize;
+    let mut i = 0;
+    let mut steps = 0;
+    while i < commands.len() {
+        steps += 1;
+        if steps > STEP_LIMIT {
+            return None;
+        }
+        match commands[i] {
+            Command::CursorRight(amount) => {
+                pointer += amount as usize;
+                if pointer >= tape.len() {
+                    tape.resize(pointer + 1, 0);
+                }
+            },
+            Command::CursorLeft(amount) => pointer = pointer.checked_sub(amount as usize)?,
+            Command::Add(amount) => tape[pointer] += amount,
+            Command::Subtract(amount) => tape[pointer] = tape[pointer].checked_sub(amount)?,
+            Command::LoopStart if tape[pointer] == 0 => i = jumps[i],
+            Command::LoopEnd if tape[pointer] != 0 => i = jumps[i],
+            _ => {}
+        }
+        i += 1;
+    }
+    return Some(tape);
+}
+
+/// for every loop command, the index of the one it pairs with
+fn match_loops(commands:&[Command]) -> Option<Vec<usize>> {
+    let mut jumps = vec![0; commands.len()];
+    let mut open = vec![];
+    for (i, command) in commands.iter().enumerate() {
+        match command {
+            Command::LoopStart => open.push(i),
+            Command::LoopEnd => {
+                let start = open.pop()?;
+                jumps[start] = i;
+                jumps[i] = start;
+            },
+            _ => {}
+        }
+    }
+    if !open.is_empty() {
+        return None;
+    }
+    return Some(jumps);
+}
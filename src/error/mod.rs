@@ -0,0 +1,236 @@
+use std::fmt;
+
+/// Everything that can go wrong between reading a recipe and emitting bytecode
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    Parse(ParseError),
+    Plan(PlanError),
+    Compile(CompileError),
+    Profile(ProfileError),
+    Io{path:String, msg:String},
+    CheckFailed{failed:usize}
+}
+
+/// Positions count characters from the start of the recipe, starting at 0
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseError {
+    Syntax{position:u32, msg:String},
+    UnknownSubstitute{position:u32, name:String},
+    NotAConstant{position:u32, field:&'static str},
+    /// `node` could come to more units than a volume can count
+    TooLarge{position:u32, node:String}
+}
+
+/// Nodes are given in recipe syntax and reservoirs are 1-indexed, as they appear in the output.
+/// `action` is the number of actions planned before the failure.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PlanError {
+    /// `reservoir` is the one it was headed for, if it had been given one yet
+    NoReservoirLargeEnough{node:String, size:u32, largest:u32, reservoir:Option<u32>},
+    /// splitting `node` into beakers would take more of them than the machine has reservoirs
+    TooLargeForMachine{node:String, size:u32, parts:u32, reservoirs:u32},
+    NoEmptyReservoir{node:String, action:usize},
+    NotEnoughReservoirs{node:String, needed:u32, available:u32},
+    TooManyChemicals{count:usize, reservoirs:u32},
+    MissingIngredient{node:String, ingredient:String, action:usize},
+    NotEnoughStock{chemical:String, needed:u32, available:u32},
+    NoSuchReservoir{reservoir:u32, reservoirs:u32},
+    ReservoirLoadedTwice{reservoir:u32},
+    LayoutOutOfRange{reagent:String, reservoir:u32, reservoirs:u32},
+    LayoutPinnedTwice{reagent:String, first:u32, second:u32},
+    LayoutSlotConflict{first:String, second:String, reservoir:u32},
+    LayoutFull{unpinned:usize, free:usize},
+    BadDose{dose:u32, max:u32, unit:&'static str},
+    NotEnoughProduct{product:String, wanted:u32, made:u32, unit:&'static str},
+    ContradictoryOrder{node:String, ingredients:String},
+    OverSubscribed{node:String, numerator:u32, denominator:u32},
+    InexactSplit{node:String, part:String, parent:u32, numerator:u32, denominator:u32},
+    UnknownOrderReference{node:String, name:String},
+    /// the planner tried to mix `node` before everything in it was mixed
+    NotALeaf{node:String}
+}
+
+/// Something odd about a recipe that planning carries on past
+#[derive(Debug, Clone, Hash, PartialEq, Eq)]
+pub enum PlanWarning {
+    UnderSubscribed{node:String, numerator:u32, denominator:u32},
+    RoundingOvershoot{node:String, excess:u32}
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProfileError {
+    UnknownKey{key:String},
+    BadValue{key:String, value:String},
+    NoReservoirs,
+    NoBeakers,
+    TargetIsReservoir{name:&'static str, target:u32, reservoirs:u32},
+    TargetsCollide{first:&'static str, second:&'static str, target:u32},
+    TooManyDecimals{decimals:u32, most:u32}
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CompileError {
+    MissingConstant{constant:u32, action:usize},
+    /// running the constant prologue did not leave `constant` in `cell`
+    BadConstant{constant:u32, cell:u32},
+    /// `--locate` was given an offset past the last command
+    NoSuchOffset{offset:usize, length:usize}
+}
+
+/// Something the machine cannot do exactly, that compiling carries on past
+#[derive(Debug, Clone, Hash, PartialEq, Eq)]
+pub enum CompileWarning {
+    /// an amount finer than the machine takes, as planned and as encoded, both in units
+    RoundingLoss{action:usize, planned:String, encoded:String}
+}
+
+impl ParseError {
+    pub fn with_msg(position:u32, msg:&str) -> ParseError {
+        return ParseError::Syntax{position, msg:msg.to_string()};
+    }
+
+    pub fn position(&self) -> u32 {
+        match self {
+            ParseError::Syntax{position, ..} => *position,
+            ParseError::UnknownSubstitute{position, ..} => *position,
+            ParseError::NotAConstant{position, ..} => *position,
+            ParseError::TooLarge{position, ..} => *position
+        }
+    }
+
+    pub fn at(self, new_position:u32) -> ParseError {
+        match self {
+            ParseError::Syntax{msg, ..} => ParseError::Syntax{position:new_position, msg},
+            ParseError::UnknownSubstitute{name, ..} => ParseError::UnknownSubstitute{position:new_position, name},
+            ParseError::NotAConstant{field, ..} => ParseError::NotAConstant{position:new_position, field},
+            ParseError::TooLarge{node, ..} => ParseError::TooLarge{position:new_position, node}
+        }
+    }
+}
+
+impl PlanError {
+    /// the same error, naming the (1-indexed) reservoir it happened in where it says which
+    pub fn in_reservoir(self, reservoir:u32) -> PlanError {
+        match self {
+            PlanError::NoReservoirLargeEnough{node, size, largest, ..} => PlanError::NoReservoirLargeEnough{node, size, largest, reservoir:Some(reservoir)},
+            err => err
+        }
+    }
+}
+
+impl From<ParseError> for Error {
+    fn from(err:ParseError) -> Error {
+        return Error::Parse(err);
+    }
+}
+
+impl From<PlanError> for Error {
+    fn from(err:PlanError) -> Error {
+        return Error::Plan(err);
+    }
+}
+
+impl From<ProfileError> for Error {
+    fn from(err:ProfileError) -> Error {
+        return Error::Profile(err);
+    }
+}
+
+impl From<CompileError> for Error {
+    fn from(err:CompileError) -> Error {
+        return Error::Compile(err);
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f:&mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Parse(err) => write!(f, "{}", err),
+            Error::Plan(err) => write!(f, "{}", err),
+            Error::Compile(err) => write!(f, "{}", err),
+            Error::Profile(err) => write!(f, "{}", err),
+            Error::Io{path, msg} => write!(f, "could not read {}: {}", path, msg),
+            Error::CheckFailed{failed} => write!(f, "{} checks failed", failed)
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f:&mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseError::Syntax{position, msg} => write!(f, "parse error at character {}: {}", position, msg),
+            ParseError::UnknownSubstitute{position, name} => write!(f, "unknown substitute *{} at character {}", name, position),
+            ParseError::NotAConstant{position, field} => write!(f, "the {} at character {} must be a plain number, not a $ fraction", field, position),
+            ParseError::TooLarge{position, node} => write!(f, "{} at character {} comes to more than {}u", node, position, u32::MAX)
+        }
+    }
+}
+
+impl fmt::Display for PlanError {
+    fn fmt(&self, f:&mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PlanError::NoReservoirLargeEnough{node, size, largest, reservoir:Some(reservoir)} => write!(f, "{}u of {} does not fit in r{}, the largest beaker holds {}u", size, node, reservoir, largest),
+            PlanError::NoReservoirLargeEnough{node, size, largest, reservoir:None} => write!(f, "{}u of {} does not fit in the largest reservoir ({}u)", size, node, largest),
+            PlanError::NoEmptyReservoir{node, action} => write!(f, "no empty reservoir left to mix {} (after action {})", node, action),
+            PlanError::TooLargeForMachine{node, size, parts, reservoirs} => write!(f, "{}u of {} needs at least {} full beakers, but the machine only has {} reservoirs", size, node, parts, reservoirs),
+            PlanError::NotEnoughReservoirs{node, needed, available} => write!(f, "no mixing order fits {} in {} reservoirs, the best order needs {}", node, available, needed),
+            PlanError::TooManyChemicals{count, reservoirs} => write!(f, "{} chemicals need loading, but the machine only has {} reservoirs", count, reservoirs),
+            PlanError::MissingIngredient{node, ingredient, action} => write!(f, "no reservoir holds enough {} to mix {} (after action {})", ingredient, node, action),
+            PlanError::NotEnoughStock{chemical, needed, available} => write!(f, "not enough {} loaded: need {}u, have {}u", chemical, needed, available),
+            PlanError::NoSuchReservoir{reservoir, reservoirs} => write!(f, "reservoir r{} does not exist, the machine has r1 to r{}", reservoir, reservoirs),
+            PlanError::ReservoirLoadedTwice{reservoir} => write!(f, "reservoir r{} is loaded twice", reservoir),
+            PlanError::LayoutOutOfRange{reagent, reservoir, reservoirs} => write!(f, "layout pins {} to r{}, but the machine only has r1 to r{}", reagent, reservoir, reservoirs),
+            PlanError::LayoutPinnedTwice{reagent, first, second} => write!(f, "layout pins {} to both r{} and r{}", reagent, first, second),
+            PlanError::LayoutSlotConflict{first, second, reservoir} => write!(f, "layout pins both {} and {} to r{}", first, second, reservoir),
+            PlanError::LayoutFull{unpinned, free} => write!(f, "{} reagents are not pinned by the layout, but only {} reservoirs are left unpinned", unpinned, free),
+            PlanError::BadDose{dose, max, unit} => write!(f, "a {} dose must be between 1u and {}u, got {}u", unit, max, dose),
+            PlanError::NotEnoughProduct{product, wanted, made, unit} => write!(f, "{} {}s of {} were asked for, but only {} can be made", wanted, unit, product, made),
+            PlanError::OverSubscribed{node, numerator, denominator} => write!(f, "the $ parts of {} ask for {}/{} of it", node, numerator, denominator),
+            PlanError::InexactSplit{node, part, parent, numerator, denominator} => write!(f, "{} is {}/{} of {}u in {}, which is not a whole number of units", part, numerator, denominator, parent, node),
+            PlanError::ContradictoryOrder{node, ingredients} => write!(f, "the ordering constraints on {} in {} contradict each other", ingredients, node),
+            PlanError::UnknownOrderReference{node, name} => write!(f, "an ingredient of {} is ordered after {}, which is not in the group", node, name),
+            PlanError::NotALeaf{node} => write!(f, "tried to mix {} before its ingredients", node)
+        }
+    }
+}
+
+impl fmt::Display for PlanWarning {
+    fn fmt(&self, f:&mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PlanWarning::UnderSubscribed{node, numerator, denominator} => write!(f, "the $ parts of {} only add up to {}/{} of it", node, numerator, denominator),
+            PlanWarning::RoundingOvershoot{node, excess} => write!(f, "rounding up the $ parts of {} asks for {}u more than it holds", node, excess)
+        }
+    }
+}
+
+impl fmt::Display for ProfileError {
+    fn fmt(&self, f:&mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProfileError::UnknownKey{key} => write!(f, "unknown machine profile setting {}", key),
+            ProfileError::BadValue{key, value} => write!(f, "machine profile setting {} should be a number, got {}", key, value),
+            ProfileError::NoReservoirs => write!(f, "the machine profile has no reservoirs"),
+            ProfileError::NoBeakers => write!(f, "the machine profile has no beakers"),
+            ProfileError::TargetIsReservoir{name, target, reservoirs} => write!(f, "the {} target {} is also reservoir r{} of {}", name, target, target, reservoirs),
+            ProfileError::TargetsCollide{first, second, target} => write!(f, "the {} and {} targets are both {}", first, second, target),
+            ProfileError::TooManyDecimals{decimals, most} => write!(f, "volumes can have at most {} decimals, got {}", most, decimals)
+        }
+    }
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f:&mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CompileError::MissingConstant{constant, action} => write!(f, "constant {} was never written to the tape (needed by action {})", constant, action),
+            CompileError::BadConstant{constant, cell} => write!(f, "running the program's prologue does not leave {} in tape cell {}", constant, cell),
+            CompileError::NoSuchOffset{offset, length} => write!(f, "character {} is past the last command of the {} character program", offset, length)
+        }
+    }
+}
+
+impl fmt::Display for CompileWarning {
+    fn fmt(&self, f:&mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CompileWarning::RoundingLoss{action, planned, encoded} => write!(f, "action {} was planned with {}u, but the machine only takes {}u", action, planned, encoded)
+        }
+    }
+}
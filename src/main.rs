@@ -5,10 +5,17 @@ mod chemicals;
 mod parser;
 mod calculator;
 mod compiler;
+mod error;
+mod profile;
+mod check;
 
-use chemicals::{Chemical, ChemToken, NumberToken, NumberOperator};
-use calculator::{Action, ChemState};
+use chemicals::{AddOrder, BuildOrder, Chemical, ChemToken, FixedPoint, NumberToken, NumberOperator, Rounding};
+use error::{Error, ParseError, PlanError, PlanWarning, CompileError, CompileWarning, ProfileError};
+use profile::{MachineProfile, ProfileFlags};
+use calculator::{Action, ChemState, Layout, Machine, Objective, Optimizer, Output, Packaging, Plan, Provenance, Report, Step};
+use std::path::PathBuf;
 use compiler::CompilerFlags;
+use check::Status;
 
 // (Buf) Uncomment these lines to have the output buffered, this can provide
 // better performance but is not always intuitive behaviour.
@@ -35,29 +42,222 @@ enum Command {
     Calc{
         #[structopt(flatten)]
         flags:CompilerFlags,
-        input:String
+        #[structopt(flatten)]
+        machine:Box<ProfileFlags>,
+        /// Reservoirs already loaded in the machine, ie "1=100:hydrogen;,4=30:*OIL;". Loaded
+        /// intermediates are used instead of being rebuilt and surplus is left in place.
+        #[structopt(long)]
+        inventory:Option<String>,
+        /// Pin base reagents to reservoirs, ie "hydrogen=1,nitrogen=2". Unpinned reagents fill the
+        /// remaining reservoirs in name order.
+        #[structopt(long)]
+        layout:Option<String>,
+        /// File of reagent pins in the same format as --layout, one or more per line
+        #[structopt(long, parse(from_os_str))]
+        layout_file:Option<PathBuf>,
+        /// Search mixing orders for the shortest program ("size") or the fewest actions ("steps")
+        /// instead of taking the greedy planner's order
+        #[structopt(long, possible_values(&["size", "steps"]))]
+        optimize:Option<Objective>,
+        /// Most mixing steps tried per batch when optimizing. The greedy order is kept if nothing
+        /// better is found in time.
+        #[structopt(long, default_value = "5000")]
+        search_limit:usize,
+        /// How the product leaves the machine: pressed into pills, bottled into vials, or kept
+        /// in its reservoir
+        #[structopt(long, default_value = "pill", possible_values(&["pill", "vial", "keep"]))]
+        output:Packaging,
+        /// Units of product per pill or vial. Only full doses are made and anything left over
+        /// stays in the reservoir. Defaults to the largest the machine makes, packaging everything.
+        #[structopt(long)]
+        dose:Option<u32>,
+        /// Number of pills or vials to make over all batches
+        #[structopt(long)]
+        count:Option<u32>,
+        /// File of recipes to make alongside any given on the command line, one per line
+        #[structopt(long, parse(from_os_str))]
+        recipes:Option<PathBuf>,
+        /// How $ fractions are rounded to whole units. Parts that make up the whole of a group
+        /// are always adjusted to add up to it; "exact" refuses any part that is not whole.
+        #[structopt(long, default_value = "up", possible_values(&["up", "down", "nearest", "exact"]))]
+        rounding:Rounding,
+        /// Decimal places of a unit to plan quantities in, ie 2 for hundredths. Amounts are
+        /// encoded at that precision if the machine's decimals allow it, otherwise rounded to
+        /// what it takes with a warning for each.
+        #[structopt(long, default_value = "0", possible_values(&["0", "1", "2", "3"]))]
+        fixed_point:u32,
+        /// Print every action with the reservoir table after it, marking reservoirs whose
+        /// volume changed with a *
+        #[structopt(long)]
+        trace:bool,
+        /// Print which part of the recipe the command at this character of the bytecode,
+        /// counting from 0, was compiled from
+        #[structopt(long)]
+        locate:Option<usize>,
+        /// What to print for the plan: the compiled program ("bytecode"), the program split per
+        /// constant and action with the offset, length and meaning of each ("listing"), or
+        /// numbered steps for making the recipe by hand ("instructions")
+        #[structopt(long, default_value = "bytecode", possible_values(&["bytecode", "listing", "instructions"]))]
+        emit:Emit,
+        /// Recipes to make in one program. Their base reagents are loaded together and each
+        /// product is packaged on its own.
+        #[structopt(required_unless = "recipes")]
+        input:Vec<String>
+    },
+    /// Check recipes can be made without planning them, printing every rule checked. Fails if
+    /// any rule does.
+    Check {
+        #[structopt(flatten)]
+        machine:ProfileFlags,
+        /// Pin base reagents to reservoirs, as for calc
+        #[structopt(long)]
+        layout:Option<String>,
+        /// File of reagent pins, as for calc
+        #[structopt(long, parse(from_os_str))]
+        layout_file:Option<PathBuf>,
+        /// File of recipes to check alongside any given on the command line, one per line
+        #[structopt(long, parse(from_os_str))]
+        recipes:Option<PathBuf>,
+        /// How $ fractions are rounded to whole units. Parts that make up the whole of a group
+        /// are always adjusted to add up to it; "exact" refuses any part that is not whole.
+        #[structopt(long, default_value = "up", possible_values(&["up", "down", "nearest", "exact"]))]
+        rounding:Rounding,
+        /// Recipes to check as one program
+        #[structopt(required_unless = "recipes")]
+        input:Vec<String>
     },
     /// List known premade chem formulas that are available to substitute.
     List {}
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Emit {
+    Bytecode,
+    Listing,
+    Instructions
+}
+
+impl std::str::FromStr for Emit {
+    type Err = String;
+
+    fn from_str(string:&str) -> Result<Emit, String> {
+        match string {
+            "bytecode" => Ok(Emit::Bytecode),
+            "listing" => Ok(Emit::Listing),
+            "instructions" => Ok(Emit::Instructions),
+            _ => Err(format!("unknown emit {}, expected bytecode, listing or instructions", string))
+        }
+    }
+}
 
 fn main() {
     let args = Cli::from_args();
-    match args.command {
-        Command::Calc {input, flags} => {
-            let (chem, total_quantity) =  parser::parse(input);
-            let chem = chem.unwrap();
-            let tree = calculator::ChemTree::deconstruct(&chem);
-            let mut tree = tree.clone();
-            tree.initial_state.multiply(total_quantity);
-            let (actions, sizes) = calculator::compute_actions(&tree, total_quantity);
-            print_required_state(&sizes, &tree.initial_state);
-            println!("{:?}\n", actions);
-            let commands = compiler::compile(&actions, &flags);
-            println!("{:?}\n", commands);
-            let code = compiler::to_bytecode(&commands);
-            println!("{}", code);   
+    if let Err(err) = run(args.command) {
+        eprintln!("error: {}", err);
+        eprintln!("hint: {}", hint(&err));
+        std::process::exit(1);
+    }
+}
+
+fn run(command:Command) -> Result<(), Error> {
+    match command {
+        Command::Calc {input, flags, machine, inventory, layout, layout_file, optimize, search_limit, output, dose, count, recipes, rounding, fixed_point, trace, locate, emit} => {
+            let machine_profile = machine.load()?;
+            let units = FixedPoint::new(fixed_point);
+            let profile = machine_profile.in_units(units);
+            let compile_profile = machine_profile.in_units(machine_profile.machine_units());
+            let mut products = read_products(input, recipes)?;
+            for (token, _) in &mut products {
+                token.scale_quantities(units.scale())?;
+            }
+            let layout = read_layout(layout, layout_file)?;
+            let tree = calculator::ChemTree::deconstruct(&products, &layout, &profile, rounding)?;
+            for warning in &tree.warnings {
+                eprintln!("warning: {}", warning);
+            }
+            for (token, _) in &products {
+                let mut token = token.clone();
+                token.set_concrete_quantity(0, rounding);
+                print!("{}", token.expanded_text(0, units));
+            }
+            println!();
+            let initial_state = match inventory {
+                Some(inventory) => {
+                    let mut inventory = parser::parse_inventory(inventory)?;
+                    for (_, chem) in &mut inventory {
+                        chem.scale_quantities(units.scale()).map_err(|err| err.at(0))?;
+                        chem.set_concrete_quantity(0, Rounding::Up);
+                    }
+                    ChemState::from_inventory(&inventory, &profile)?
+                },
+                None => tree.initial_state.clone()
+            };
+            let optimizer = optimize.map(|objective| Optimizer {
+                cost:match objective {
+                    Objective::Size => Box::new(|actions:&Vec<Action>| match compiler::compile(&compiler::encode_amounts(actions, units, compile_profile.units).0, &flags, &compile_profile) {
+                        Ok(commands) => compiler::to_bytecode(&commands).len(),
+                        Err(_) => usize::MAX
+                    }),
+                    Objective::Steps => Box::new(|actions:&Vec<Action>| actions.len())
+                },
+                limit:search_limit
+            });
+            let output = Output {packaging:output, dose:dose.map(|dose| dose * units.scale()), count};
+            let plan = calculator::compute_actions(&tree, &initial_state, optimizer.as_ref(), &output)?;
+            print_required_state(&plan.sizes, &initial_state);
+            print_report(&Report::new(&tree, &initial_state, &plan), &flags);
+            if trace {
+                print_trace(&initial_state, &plan);
+            }
+            if emit == Emit::Instructions {
+                print_instructions(&initial_state, &plan);
+                // the program is only compiled to find where --locate points
+                if locate.is_none() {
+                    return Ok(());
+                }
+            }
+            let (actions, warnings) = compiler::encode_amounts(&plan.actions, units, compile_profile.units);
+            for warning in &warnings {
+                eprintln!("warning: {}", warning);
+            }
+            let commands = compiler::compile(&actions, &flags, &compile_profile)?;
+            match emit {
+                Emit::Listing => print_listing(&initial_state, &plan, &commands),
+                Emit::Bytecode => {
+                    println!("{:?}\n", actions);
+                    println!("{:?}\n", commands.iter().map(|x| &x.command).collect::<Vec<&compiler::Command>>());
+                    let code = compiler::to_bytecode(&commands);
+                    println!("{}", code);
+                },
+                Emit::Instructions => {}
+            }
+            if let Some(offset) = locate {
+                match compiler::locate(&commands, offset) {
+                    Some(provenance) => println!("\ncharacter {}: {}", offset, provenance_text(provenance)),
+                    None => return Err(Error::Compile(CompileError::NoSuchOffset{offset, length:compiler::to_bytecode(&commands).len()}))
+                }
+            }
+        },
+        Command::Check {machine, layout, layout_file, recipes, rounding, input} => {
+            let profile = machine.load()?;
+            let layout = read_layout(layout, layout_file)?;
+            let mut texts = input;
+            if let Some(path) = recipes {
+                texts.extend(parser::recipe_lines(&read_file(&path)?).into_iter().map(|(_, recipe)| recipe));
+            }
+            let diagnostics = check::check(&texts, &layout, &profile, rounding);
+            for diagnostic in &diagnostics {
+                let status = match diagnostic.status {
+                    Status::Pass => "ok  ",
+                    Status::Warn => "warn",
+                    Status::Fail => "FAIL"
+                };
+                println!("{} {}: {}", status, diagnostic.rule, diagnostic.message);
+            }
+            let failed = diagnostics.iter().filter(|x| x.status == Status::Fail).count();
+            if failed > 0 {
+                return Err(Error::CheckFailed{failed});
+            }
         },
         Command::List {} => {
             for chemical_name in parser::SUB_MAP.keys() {
@@ -65,15 +265,471 @@ fn main() {
             }
         }
     }
+    return Ok(());
+}
+
+/// what the user can do about each error
+fn hint(err:&Error) -> &'static str {
+    match err {
+        Error::Parse(ParseError::Syntax{..}) => "recipes look like 50:($/2:hydrogen;$/2:*OIL;)@374; with every name ending in ;",
+        Error::Parse(ParseError::UnknownSubstitute{..}) => "run the list command to see the available substitutes",
+        Error::Parse(ParseError::NotAConstant{..}) => "only ingredient quantities may be written as $ fractions of their parent",
+        Error::Parse(ParseError::TooLarge{..}) => "use smaller quantities, or fewer --fixed-point decimals",
+        Error::Plan(PlanError::NoReservoirLargeEnough{..}) => "reduce the recipe quantity, or produce it in several batches with <n>x",
+        Error::Plan(PlanError::NoEmptyReservoir{..}) => "the recipe needs more reservoirs than the machine has; try a smaller batch count or pre-load intermediates with --inventory",
+        Error::Plan(PlanError::TooLargeForMachine{..}) => "reduce the recipe quantity, or use a machine with more reservoirs or larger beakers",
+        Error::Plan(PlanError::NotEnoughReservoirs{..}) => "every mixing order was tried; use a machine with more reservoirs, reduce the batch count or pre-load intermediates with --inventory",
+        Error::Plan(PlanError::TooManyChemicals{..}) => "split the recipe, or pre-load some intermediates with --inventory",
+        Error::Plan(PlanError::MissingIngredient{..}) => "check that --inventory loads every ingredient the recipe draws on",
+        Error::Plan(PlanError::NotEnoughStock{..}) => "load more of the reagent with --inventory, or reduce the recipe quantity",
+        Error::Plan(PlanError::NoSuchReservoir{..}) => "reservoirs are numbered from 1",
+        Error::Plan(PlanError::ReservoirLoadedTwice{..}) => "give each reservoir at most one --inventory entry",
+        Error::Plan(PlanError::LayoutOutOfRange{..}) => "reservoirs are numbered from 1",
+        Error::Plan(PlanError::LayoutPinnedTwice{..}) => "pin each reagent to a single reservoir",
+        Error::Plan(PlanError::LayoutSlotConflict{..}) => "pin each reservoir to a single reagent",
+        Error::Plan(PlanError::LayoutFull{..}) => "pin fewer reagents, or pin the recipe's own reagents so the rest have room",
+        Error::Plan(PlanError::BadDose{..}) => "the largest pill and vial are set by --max-pill and --max-vial",
+        Error::Plan(PlanError::NotEnoughProduct{..}) => "ask for fewer or smaller doses, or make more batches with <n>x",
+        Error::Plan(PlanError::OverSubscribed{..}) => "the $ parts of a group can add up to at most $/1, check the denominators",
+        Error::Plan(PlanError::InexactSplit{..}) => "make the quantity divisible by every denominator below it, or pick another --rounding",
+        Error::Plan(PlanError::ContradictoryOrder{..}) => "check for ingredients ordered after each other, or after one in a later heat stage",
+        Error::Plan(PlanError::UnknownOrderReference{..}) => "!after: names a base reagent or *SUBSTITUTE in the same group",
+        Error::Plan(PlanError::NotALeaf{..}) => "this is a planner bug, please report the recipe that caused it",
+        Error::Compile(CompileError::MissingConstant{..}) => "this is a compiler bug, please report the recipe that caused it",
+        Error::Compile(CompileError::BadConstant{..}) => "this is a compiler bug, please report the recipe that caused it",
+        Error::Compile(CompileError::NoSuchOffset{..}) => "offsets count characters of the printed bytecode from 0, and the final ~ has none",
+        Error::Profile(ProfileError::UnknownKey{..}) => "profile settings are reservoirs, beakers, max_pill, max_vial, pill_target, vial_target, eject_target, transfer_all, min_temp, max_temp and decimals",
+        Error::Profile(ProfileError::BadValue{..}) => "profile values are whole numbers, beakers is a comma separated list of them",
+        Error::Profile(ProfileError::NoReservoirs) => "set reservoirs to at least 1",
+        Error::Profile(ProfileError::NoBeakers) => "list at least one beaker capacity, ie --beakers 50,100",
+        Error::Profile(ProfileError::TargetIsReservoir{..}) => "special targets must be numbered above the last reservoir",
+        Error::Profile(ProfileError::TargetsCollide{..}) => "give the pill, vial and eject targets different numbers",
+        Error::Profile(ProfileError::TooManyDecimals{..}) => "machines count volumes in at most thousandths of a unit",
+        Error::Io{..} => "check the path and its permissions",
+        Error::CheckFailed{..} => "fix the rules marked FAIL above before loading the machine"
+    }
+}
+
+fn read_file(path:&PathBuf) -> Result<String, Error> {
+    match std::fs::read_to_string(path) {
+        Ok(text) => return Ok(text),
+        Err(err) => return Err(Error::Io{path:path.display().to_string(), msg:err.to_string()})
+    }
+}
+
+fn read_products(input:Vec<String>, recipes:Option<PathBuf>) -> Result<Vec<(ChemToken, u32)>, Error> {
+    let mut products = vec![];
+    for recipe in input {
+        products.push(parser::parse(recipe)?);
+    }
+    if let Some(path) = recipes {
+        products.extend(parser::parse_recipes(read_file(&path)?)?);
+    }
+    return Ok(products);
+}
+
+fn read_layout(layout:Option<String>, layout_file:Option<PathBuf>) -> Result<Layout, Error> {
+    let mut pins = vec![];
+    if let Some(path) = layout_file {
+        pins.extend(parser::parse_layout(read_file(&path)?)?);
+    }
+    if let Some(layout) = layout {
+        pins.extend(parser::parse_layout(layout)?);
+    }
+    return Ok(Layout {pins});
+}
+
+fn print_required_state(sizes:&[u32], state:&ChemState) {
+    for line in required_state(sizes, state) {
+        println!("{}", line);
+    }
+}
+
+/// each reservoir's starting contents against the beaker it needs
+fn required_state(sizes:&[u32], state:&ChemState) -> Vec<String> {
+    let units = state.profile.units;
+    return sizes.iter().enumerate().map(|(i, size)| {
+        let (name, amount) = match state.get(i).contents {
+            Some(contents) => (contents.short_text(), contents.concrete_quantity.unwrap()),
+            None => ("None".to_string(), 0)
+        };
+        format!("r{}: ({}/{}) {}", i+1, units.text(amount), units.text(*size), name)
+    }).collect();
+}
+
+fn print_report(report:&Report, flags:&CompilerFlags) {
+    let units = |volume:u32| report.units.text(volume);
+    println!("\nafter the program runs:");
+    for (reservoir, volume, contents) in &report.contents {
+        match contents {
+            Some(contents) => println!("r{}: {}u {}", reservoir, units(*volume), contents.short_text()),
+            None if *volume > 0 => println!("r{}: {}u of residue", reservoir, units(*volume)),
+            None => {}
+        }
+    }
+    println!("packaged: {}u, kept: {}u", units(report.packaged), units(report.kept));
+    if flags.sideproduct_pills {
+        println!("side pills: {}u", units(report.ejected + report.emptied));
+    } else {
+        println!("ejected: {}u, side pills: {}u", units(report.ejected), units(report.emptied));
+    }
+    for (node, excess) in &report.rounding {
+        let direction = if *excess > 0.0 {"up"} else {"down"};
+        println!("rounded {}: {:.2}u at {}", direction, report.units.units(excess.abs()), node);
+    }
+    println!("base reagent efficiency: {:.1}% of {}u loaded\n", report.efficiency(), units(report.loaded));
+}
+
+fn print_instructions(initial_state:&ChemState, plan:&Plan) {
+    let mut machine = Machine::new(initial_state, &plan.sizes);
+    let mut steps = vec![];
+    for (i, slot) in machine.reservoirs.iter().enumerate() {
+        if slot.volume > 0 {
+            steps.push(format!("Fill beaker {} with {}u of {}", i+1, machine.units.text(slot.volume), slot.english()));
+        }
+    }
+    for action in &plan.actions {
+        steps.extend(machine.instruct(action));
+        machine.apply(action);
+    }
+    for (i, step) in steps.iter().enumerate() {
+        println!("{}. {}", i+1, step);
+    }
+}
+
+fn print_listing(initial_state:&ChemState, plan:&Plan, commands:&Vec<compiler::Emitted>) {
+    let mut machine = Machine::new(initial_state, &plan.sizes);
+    for (offset, code, part) in compiler::listing(commands) {
+        let comment = match part {
+            compiler::Part::Constant{cell, value} => format!("cell {} = {}", cell, value),
+            compiler::Part::Action(i) => {
+                let action = &plan.actions[i];
+                let description = machine.describe(action);
+                machine.apply(action);
+                format!("{}: {}", i, description)
+            },
+            compiler::Part::End => "end".to_string()
+        };
+        println!("{:>5} {:>4}  {:<24}  # {}", offset, code.len(), code, comment);
+    }
+}
+
+/// where in the recipe a command came from, in words
+fn provenance_text(provenance:&Provenance) -> String {
+    let origin = match provenance.span {
+        Some((start, end)) => format!("characters {} to {} of the recipe", start, end),
+        None => return "the program's setup, not any one part of the recipe".to_string()
+    };
+    return match &provenance.substitute {
+        Some(substitute) => format!("{}, in *{}", origin, substitute),
+        None => origin
+    };
 }
 
-fn print_required_state(sizes:&Vec<u32>, state:&ChemState) {
-    for i in 0..sizes.len() {
-        let state = state.get(i);
-        let name:String = if state.contents.is_some() {state.contents.as_ref().unwrap().chemical.name.as_ref().unwrap_or(&"None".to_string()).to_string()} else {"None".to_string()};
-        let amount = if state.contents.is_some() {state.contents.as_ref().unwrap().concrete_quantity.unwrap()} else {0};
-        let size = sizes[i];
-        println!("r{}: ({}/{}) {}", i+1,amount,size, name);
+fn print_trace(initial_state:&ChemState, plan:&Plan) {
+    let mut machine = Machine::new(initial_state, &plan.sizes);
+    for (i, action) in plan.actions.iter().enumerate() {
+        println!("{}: {}", i, machine.describe(action));
+        let changed = machine.apply(action);
+        for (j, slot) in machine.reservoirs.iter().enumerate() {
+            let reservoir = j as u32 + 1;
+            let marker = if changed.contains(&reservoir) {"*"} else {" "};
+            let temp = slot.temp.map(|temp| format!(" @{}K", temp)).unwrap_or_default();
+            println!("{} r{}: ({}/{}) {}{}", marker, reservoir, machine.units.text(slot.volume), machine.units.text(slot.capacity), slot.contents_text(), temp);
+        }
+        println!();
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn compile_recipe(input:&str) -> String {
+        let products = parser::parse_recipes(input.to_string()).unwrap();
+        let profile = MachineProfile::default();
+        let tree = calculator::ChemTree::deconstruct(&products, &Layout::default(), &profile, Rounding::default()).unwrap();
+        let plan = calculator::compute_actions(&tree, &tree.initial_state, None, &Output::default()).unwrap();
+        let commands = compiler::compile(&plan.actions, &CompilerFlags::default(), &profile).unwrap();
+        return compiler::to_bytecode(&commands);
+    }
+
+    fn plan_recipe(input:&str, profile:&MachineProfile, output:&Output) -> Result<Plan, PlanError> {
+        let products = parser::parse_recipes(input.to_string()).unwrap();
+        let tree = calculator::ChemTree::deconstruct(&products, &Layout::default(), profile, Rounding::default())?;
+        return calculator::compute_actions(&tree, &tree.initial_state, None, output);
+    }
+
+    /// the stock machine with `reservoirs` reservoirs, and the special targets moved above them
+    fn machine(reservoirs:u32) -> MachineProfile {
+        return MachineProfile {reservoirs, pill_target:reservoirs + 1, vial_target:reservoirs + 2, eject_target:reservoirs + 3, ..Default::default()};
+    }
+
+    #[test]
+    fn identical_input_compiles_identically() {
+        let mut recipes:Vec<String> = parser::SUB_MAP.keys().map(|name| format!("30:*{};", name)).collect();
+        recipes.push("2x20:*METH;".to_string());
+        recipes.push("3x15:*PHLOGISTON;".to_string());
+        recipes.push("50:($/3:*OIL;$/3:*SULFURIC_ACID;$/3:hydrogen;)@374;".to_string());
+        recipes.push("2x20:*OIL;\n30:*AMMONIA;".to_string());
+        for recipe in &recipes {
+            let first = compile_recipe(recipe);
+            for _ in 0..5 {
+                assert_eq!(first, compile_recipe(recipe), "{} compiled differently between runs", recipe);
+            }
+        }
+    }
+
+    #[test]
+    fn golden_output() {
+        assert_eq!(compile_recipe("30:*AMMONIA;"), "+>>+++++[<++++++>-]++++++++++>++>>++++++++++[<++++++++++>-]>+++++++++++>++++++++++++>+++++++++++++<<<<<<<<},>>>>>>>>>^------------------------------'<)@<<<<<<'>}<<<)@}>>>>'>>)@~");
+        assert_eq!(compile_recipe("2x20:*OIL;"), "++++++++++>+++>++++>+>++>>++++++++++[<++++++++++>-]>+++++++++++>++++++++++++>+++++++++++++<<<<<<<<<'>}>)@<<'>>>}<)@<<'>>>>}<<)@}>>>'>>)@<<<<<<<'>}>)@<<'>>>}<)@<<'>>>>}<<)@}>>>'>>)@~");
+    }
+
+    #[test]
+    fn required_state_names_loaded_intermediates() {
+        let profile = MachineProfile::default();
+        let mut inventory = parser::parse_inventory("1=40:hydrogen;,4=30:*OIL;".to_string()).unwrap();
+        for (_, chem) in &mut inventory {
+            chem.set_concrete_quantity(0, Rounding::Up);
+        }
+        let state = ChemState::from_inventory(&inventory, &profile).unwrap();
+        let lines = required_state(&[50, 50, 50, 50], &state);
+        assert_eq!(lines, vec!["r1: (40/50) hydrogen", "r2: (0/50) None", "r3: (0/50) None", "r4: (30/50) *OIL"]);
+    }
+
+    #[test]
+    fn base_reagents_are_packaged_from_their_stock() {
+        for (recipe, volume) in [("10:hydrogen;", 10), ("101:hydrogen;", 101)] {
+            let products = parser::parse_recipes(recipe.to_string()).unwrap();
+            let tree = calculator::ChemTree::deconstruct(&products, &Layout::default(), &MachineProfile::default(), Rounding::default()).unwrap();
+            let plan = calculator::compute_actions(&tree, &tree.initial_state, None, &Output::default()).unwrap();
+            let hydrogen = products[0].0.chemical.clone();
+            for action in &plan.actions {
+                if let Step::CreatePill{target, ..} = action.step {
+                    let source = tree.initial_state.get(target as usize - 1).contents;
+                    assert_eq!(source.map(|x| x.chemical), Some(hydrogen.clone()), "{} pressed a pill from r{}", recipe, target);
+                }
+            }
+            assert_eq!(Report::new(&tree, &tree.initial_state, &plan).packaged, volume, "{}", recipe);
+        }
+    }
+
+    #[test]
+    fn oversized_recipes_are_split() {
+        // meth mixes to about a third more than its quantity, and every part is packaged in full
+        for (recipe, reservoirs, packaged) in [("150:*METH;", 10, 200), ("200:*METH;", 12, 272), ("3x100:*METH;", 14, 408), ("101:hydrogen;", 10, 101)] {
+            let profile = machine(reservoirs);
+            let products = parser::parse_recipes(recipe.to_string()).unwrap();
+            let tree = calculator::ChemTree::deconstruct(&products, &Layout::default(), &profile, Rounding::default()).unwrap();
+            let plan = match calculator::compute_actions(&tree, &tree.initial_state, None, &Output::default()) {
+                Ok(plan) => plan,
+                Err(err) => panic!("{} failed to plan: {}", recipe, err)
+            };
+            let mut machine = Machine::new(&tree.initial_state, &plan.sizes);
+            for action in &plan.actions {
+                if let Step::CreatePill{target, ..} = action.step {
+                    let source = &machine.reservoirs[target as usize - 1];
+                    let expected = if recipe.contains("METH") {source.contents.len() > 1} else {source.contents == ["hydrogen"]};
+                    assert!(expected, "{} pressed a pill of {} from r{}", recipe, source.contents_text(), target);
+                }
+                machine.apply(action);
+            }
+            assert_eq!(Report::new(&tree, &tree.initial_state, &plan).packaged, packaged, "{}", recipe);
+        }
+    }
+
+    #[test]
+    fn packaging_uses_the_mixed_volume() {
+        // 40u of ammonia mixes 40u of hydrogen with 14u of nitrogen
+        let vials = Output {packaging:Packaging::Vial, ..Default::default()};
+        let plan = plan_recipe("40:*AMMONIA;", &MachineProfile::default(), &vials).unwrap();
+        assert_eq!(plan.actions.iter().filter(|x| matches!(x.step, Step::CreateBottle{..})).count(), 2);
+        assert!(plan.remainders.is_empty());
+
+        let plan = plan_recipe("2x40:*AMMONIA;", &MachineProfile::default(), &vials).unwrap();
+        assert_eq!(plan.actions.iter().filter(|x| matches!(x.step, Step::CreateBottle{..})).count(), 4);
+        assert!(plan.remainders.is_empty());
+
+        let doses = Output {packaging:Packaging::Vial, dose:Some(15), count:Some(5)};
+        let plan = plan_recipe("2x40:*AMMONIA;", &MachineProfile::default(), &doses).unwrap();
+        let left:Vec<u32> = plan.remainders.iter().map(|(_, left)| left.size()).collect();
+        assert_eq!(left, vec![9, 24]);
+        for (reservoir, left) in &plan.remainders {
+            assert_eq!(plan.state.get(*reservoir as usize - 1).contents.unwrap().size(), left.size());
+        }
+    }
+
+    #[test]
+    fn mixing_in_place_keeps_the_addition_order() {
+        let profile = MachineProfile::default();
+        let inventory = parser::parse_inventory("1=10:hydrogen;,2=50:oxygen;".to_string()).unwrap();
+        let state = ChemState::from_inventory(&inventory, &profile).unwrap();
+        let first_step = |recipe:&str| {
+            let products = parser::parse_recipes(recipe.to_string()).unwrap();
+            let tree = calculator::ChemTree::deconstruct(&products, &Layout::default(), &profile, Rounding::default()).unwrap();
+            let plan = calculator::compute_actions(&tree, &state, None, &Output::default()).unwrap();
+            return plan.actions[0].step;
+        };
+        // hydrogen drains r1, but oxygen has to go in first so it cannot be mixed there
+        assert_eq!(first_step("20:($/2:hydrogen;$/2:oxygen;!first;)"), Step::Transfer{amount:10, source:2, target:3});
+        assert_eq!(first_step("20:($/2:hydrogen;!first;$/2:oxygen;)"), Step::EjectDownTo{amount:10, target:1});
+    }
+
+    #[test]
+    fn optimizing_never_loses_to_greedy() {
+        let profile = MachineProfile::default();
+        let flags = CompilerFlags::default();
+        let bytecode = |actions:&Vec<Action>| compiler::to_bytecode(&compiler::compile(actions, &flags, &profile).unwrap()).len();
+        let optimizer = Optimizer {cost:Box::new(bytecode), limit:2000};
+        for recipe in ["3x20:*METH;", "2x20:*OIL;", "3x15:*PHLOGISTON;", "30:*AMMONIA;"] {
+            let products = parser::parse_recipes(recipe.to_string()).unwrap();
+            let tree = calculator::ChemTree::deconstruct(&products, &Layout::default(), &profile, Rounding::default()).unwrap();
+            let greedy = calculator::compute_actions(&tree, &tree.initial_state, None, &Output::default()).unwrap();
+            let optimized = calculator::compute_actions(&tree, &tree.initial_state, Some(&optimizer), &Output::default()).unwrap();
+            assert!(bytecode(&optimized.actions) <= bytecode(&greedy.actions), "{} got longer when optimized", recipe);
+        }
+    }
+
+    #[test]
+    fn check_reports_each_problem_once() {
+        let recipes = vec!["20:*FOO;".to_string(), "150:*METH;".to_string()];
+        let diagnostics = check::check(&recipes, &Layout::default(), &MachineProfile::default(), Rounding::default());
+        let failed:Vec<&str> = diagnostics.iter().filter(|x| x.status == Status::Fail).map(|x| x.rule).collect();
+        assert_eq!(failed, vec!["substitutes"]);
+        // calc splits the oversized mix, so it only warns
+        assert!(diagnostics.iter().any(|x| x.rule == "beakers" && x.status == Status::Warn));
+        assert!(diagnostics.iter().any(|x| x.rule == "tree" && x.status == Status::Pass));
+        // every over-subscribed group fails, and the tree rule leaves them to the fraction rule
+        let recipes = vec!["20:($2/3:hydrogen;$2/3:($3/4:carbon;$3/4:oxygen;))".to_string(), "10:($/3:hydrogen;$/3:carbon;)".to_string()];
+        let diagnostics = check::check(&recipes, &Layout::default(), &MachineProfile::default(), Rounding::default());
+        let fractions:Vec<Status> = diagnostics.iter().filter(|x| x.rule == "fractions").map(|x| x.status).collect();
+        assert_eq!(fractions, vec![Status::Fail, Status::Fail, Status::Pass, Status::Warn]);
+        assert!(diagnostics.iter().all(|x| x.rule != "tree"));
+    }
+
+    #[test]
+    fn locate_finds_the_action_a_command_came_from() {
+        let profile = MachineProfile::default();
+        let plan = plan_recipe("50:($/2:*OIL;$/2:hydrogen;)", &profile, &Output::default()).unwrap();
+        let commands = compiler::compile(&plan.actions, &CompilerFlags::default(), &profile).unwrap();
+        for (offset, code, part) in compiler::listing(&commands) {
+            let expected = match part {
+                compiler::Part::Action(i) => Some(&plan.actions[i].provenance),
+                compiler::Part::Constant{..} => Some(&Provenance::default()),
+                compiler::Part::End => None
+            };
+            assert_eq!(compiler::locate(&commands, offset), expected);
+            assert_eq!(compiler::locate(&commands, offset + code.len() - 1), expected);
+        }
+        // the oil is mixed from characters 4 to 13, "$/2:*OIL;"
+        assert!(plan.actions.iter().any(|x| x.provenance.span == Some((4, 13)) && x.provenance.substitute.as_deref() == Some("OIL")));
+    }
+
+    /// the volume of `reagent` loaded before the plan runs, over every reservoir it is in
+    fn loaded(state:&ChemState, reagent:&str) -> u32 {
+        let chemical = parser::parse(format!("1:{};", reagent)).unwrap().0.chemical;
+        return (0..state.profile.reservoirs as usize).filter_map(|i| state.get(i).contents).filter(|x| x.chemical == chemical).map(|x| x.size()).sum();
+    }
+
+    #[test]
+    fn searching_orders_fits_pentetic_acid() {
+        assert!(plan_recipe("60:*PENTETIC;", &machine(9), &Output::default()).is_ok());
+        match plan_recipe("2x60:*PENTETIC;", &MachineProfile::default(), &Output::default()) {
+            Err(PlanError::NotEnoughReservoirs{needed, available, ..}) => assert_eq!((needed, available), (12, 10)),
+            other => panic!("expected too few reservoirs, got {:?}", other.map(|x| x.actions.len()))
+        }
+    }
+
+    #[test]
+    fn products_share_one_load() {
+        let profile = MachineProfile::default();
+        let products = parser::parse_recipes("30:*OIL;\n20:*AMMONIA;".to_string()).unwrap();
+        let tree = calculator::ChemTree::deconstruct(&products, &Layout::default(), &profile, Rounding::default()).unwrap();
+        assert_eq!(loaded(&tree.initial_state, "hydrogen"), 15 + 20);
+        let plan = calculator::compute_actions(&tree, &tree.initial_state, None, &Output::default()).unwrap();
+        let pills:Vec<(u32, u32)> = plan.actions.iter().filter(|x| matches!(x.step, Step::CreatePill{..})).map(|x| x.provenance.span.unwrap()).collect();
+        assert_eq!(pills, vec![(0, 8), (9, 21)]);
+        assert_eq!(Report::new(&tree, &tree.initial_state, &plan).packaged, 45 + 27);
+    }
+
+    #[test]
+    fn batches_share_bulk_intermediates() {
+        let profile = MachineProfile::default();
+        let products = parser::parse_recipes("2x40:($/2:*OIL;$/2:hydrogen;)".to_string()).unwrap();
+        let tree = calculator::ChemTree::deconstruct(&products, &Layout::default(), &profile, Rounding::default()).unwrap();
+        let plan = calculator::compute_actions(&tree, &tree.initial_state, None, &Output::default()).unwrap();
+        let carbon = tree.initial_state.find_chem(&parser::parse("1:carbon;".to_string()).unwrap().0.chemical).unwrap() as u32 + 1;
+        // both batches' oil is mixed at once, so carbon is only poured once
+        let pours:Vec<u32> = plan.actions.iter().filter_map(|x| match x.step {
+            Step::Transfer{amount, source, ..} if source == carbon => Some(amount),
+            _ => None
+        }).collect();
+        assert_eq!(pours, vec![20]);
+    }
+
+    #[test]
+    fn heat_stages_go_between_additions() {
+        let plan = plan_recipe("30:($/3:sugar;$/3:hydrogen;@374;$/3:carbon;@420;)", &MachineProfile::default(), &Output::default()).unwrap();
+        let steps:Vec<String> = plan.actions.iter().filter_map(|x| match x.step {
+            Step::Heat{temp, ..} => Some(format!("heat {}", temp)),
+            Step::Transfer{amount, ..} | Step::EjectDownTo{amount, ..} => Some(format!("add {}", amount)),
+            _ => None
+        }).collect();
+        assert_eq!(steps, vec!["add 10", "add 10", "heat 374", "add 10", "heat 420"]);
+    }
+
+    #[test]
+    fn rounding_sets_the_load() {
+        let products = parser::parse_recipes("10:($/3:hydrogen;$/4:carbon;)".to_string()).unwrap();
+        for (rounding, hydrogen, carbon) in [(Rounding::Up, 4, 3), (Rounding::Down, 3, 2), (Rounding::Nearest, 3, 3)] {
+            let tree = calculator::ChemTree::deconstruct(&products, &Layout::default(), &MachineProfile::default(), rounding).unwrap();
+            assert_eq!((loaded(&tree.initial_state, "hydrogen"), loaded(&tree.initial_state, "carbon")), (hydrogen, carbon), "{:?}", rounding);
+        }
+        assert!(matches!(calculator::ChemTree::deconstruct(&products, &Layout::default(), &MachineProfile::default(), Rounding::Exact), Err(PlanError::InexactSplit{..})));
+        // parts making up the whole group add up to it exactly
+        let products = parser::parse_recipes("10:($/3:hydrogen;$/3:carbon;$/3:oxygen;)".to_string()).unwrap();
+        let tree = calculator::ChemTree::deconstruct(&products, &Layout::default(), &MachineProfile::default(), Rounding::Up).unwrap();
+        assert_eq!((loaded(&tree.initial_state, "hydrogen"), loaded(&tree.initial_state, "carbon"), loaded(&tree.initial_state, "oxygen")), (4, 3, 3));
+        // shares are worked out past a u32 before dividing
+        let (mut token, _) = parser::parse("500000000:($9/9:hydrogen;)".to_string()).unwrap();
+        token.set_concrete_quantity(0, Rounding::Exact);
+        assert_eq!(token.chemical.chemicals[0].size(), 500000000);
+        assert!(token.inexact_part().is_none());
+    }
+
+    #[test]
+    fn fixed_point_amounts_are_encoded_or_warned() {
+        let units = FixedPoint::new(2);
+        let mut products = parser::parse_recipes("10:($/3:hydrogen;$/3:carbon;)".to_string()).unwrap();
+        for (token, _) in &mut products {
+            token.scale_quantities(units.scale()).unwrap();
+        }
+        for (decimals, losses) in [(0, 2), (2, 0)] {
+            let machine_profile = MachineProfile {decimals, ..Default::default()};
+            let profile = machine_profile.in_units(units);
+            let tree = calculator::ChemTree::deconstruct(&products, &Layout::default(), &profile, Rounding::Up).unwrap();
+            assert_eq!(loaded(&tree.initial_state, "hydrogen"), 334);
+            let plan = calculator::compute_actions(&tree, &tree.initial_state, None, &Output::default()).unwrap();
+            let (_, warnings) = compiler::encode_amounts(&plan.actions, units, machine_profile.machine_units());
+            assert_eq!(warnings.len(), losses, "with {} decimals", decimals);
+        }
+        let (mut token, _) = parser::parse("99999999:hydrogen;".to_string()).unwrap();
+        assert_eq!(token.scale_quantities(FixedPoint::new(3).scale()), Err(ParseError::TooLarge{position:0, node:"99999999:hydrogen;".to_string()}));
+        let (mut token, _) = parser::parse("9999999:($/2:hydrogen;$/2:oxygen;)".to_string()).unwrap();
+        assert!(token.scale_quantities(FixedPoint::new(2).scale()).is_ok());
+    }
+
+    #[test]
+    fn bad_numbers_are_parse_errors() {
+        assert_eq!(parser::parse("50:($/0:hydrogen;)".to_string()), Err(ParseError::with_msg(6, "number parse error, denominator is 0")));
+        assert_eq!(parser::parse("$/2:hydrogen;".to_string()), Err(ParseError::NotAConstant{position:0, field:"recipe quantity"}));
+        assert_eq!(parser::parse("2x$/2:hydrogen;".to_string()), Err(ParseError::NotAConstant{position:2, field:"recipe quantity"}));
+        assert_eq!(parser::parse("99999999999:hydrogen;".to_string()), Err(ParseError::with_msg(10, "number too large")));
+        assert!(parser::parse("4294967295:hydrogen;".to_string()).is_ok());
+        assert_eq!(parser::parse("10:()".to_string()), Err(ParseError::with_msg(4, "empty group")));
+        assert_eq!(parser::parse("10:($/2:hydrogen;$/2:())".to_string()).map_err(|err| err.position()), Err(22));
+        assert_eq!(parser::parse("500000000:($9/1:hydrogen;)".to_string()), Err(ParseError::TooLarge{position:11, node:"$9/1:hydrogen;".to_string()}));
+        assert_eq!(parser::parse("4294967295:(4294967295:hydrogen;1:oxygen;)".to_string()).map_err(|err| err.position()), Err(0));
+    }
+}
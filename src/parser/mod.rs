@@ -1,12 +1,6 @@
-use crate::{Chemical, ChemToken, NumberToken, NumberOperator};
-use std::collections::HashMap;
-use std::boxed::Box;
-
-#[derive(Debug, Default)]
-pub struct ParseError {
-    position:u32,
-    msg:String
-}
+use crate::{AddOrder, BuildOrder, Chemical, ChemToken, NumberToken, NumberOperator, ParseError};
+use crate::chemicals::Rounding;
+use std::collections::BTreeMap;
 
 const AMMONIA:&str = "($/1:hydrogen;$/3:nitrogen;)";
 const DIETHYLAMINE:&str = "($/2:ethanol;$/2:*AMMONIA;)@374;";
@@ -31,7 +25,7 @@ const CYANIDE:&str = "($/1:*AMMONIA;$/1:*OIL;$/1:oxygen;)@374;";
 
 
 lazy_static!{
-    pub static ref SUB_MAP: HashMap<&'static str, &'static str> = [
+    pub static ref SUB_MAP: BTreeMap<&'static str, &'static str> = [
         ("FLUOROSULFURIC_ACID", FLUOROSULFURIC_ACID),
         ("METH", METH_FORMULA),
         ("AMMONIA", AMMONIA),
@@ -56,64 +50,217 @@ lazy_static!{
     ].iter().copied().collect();
 }
 
-fn get_substitute_formula(name:String) -> &'static str {
+fn get_substitute_formula(name:String, position:u32) -> Result<&'static str, ParseError> {
     let name = name.to_ascii_uppercase();
     let name = name.as_str();
     if !SUB_MAP.contains_key(&name) {
-        panic!("MISSING: {}", name);
+        return Err(ParseError::UnknownSubstitute{position, name:name.to_string()});
     }
-    return SUB_MAP[name];
+    return Ok(SUB_MAP[name]);
 }
 
-impl ParseError {
-    fn new(position:u32) -> ParseError {
-        return ParseError{position, ..Default::default()}
+/// parse a recipe, returning it along with the number of times it should be produced
+pub fn parse(string:String) -> Result<(ChemToken, u32), ParseError> {
+    let length = string.chars().count() as u32;
+    // internally positions count the tokens left to read, flip them to count from the start
+    let (mut chem, batches) = parse_tokens(tokenize(string)).map_err(|err| {
+        let remaining = err.position();
+        err.at(length - remaining)
+    })?;
+    move_spans(&mut chem, &|remaining| length - remaining);
+    chem.check_size()?;
+    return Ok((chem, batches));
+}
+
+/// recipes one per line, with anything after a '#' ignored. Error positions count characters
+/// from the start of the text.
+pub fn parse_recipes(string:String) -> Result<Vec<(ChemToken, u32)>, ParseError> {
+    let mut recipes = vec![];
+    for (start, recipe) in recipe_lines(&string) {
+        let (mut chem, batches) = parse(recipe).map_err(|err| {
+            let position = err.position();
+            err.at(start + position)
+        })?;
+        move_spans(&mut chem, &|position| start + position);
+        recipes.push((chem, batches));
     }
+    return Ok(recipes);
+}
 
+/// the recipes in text of the format `parse_recipes` takes, with the character each starts at
+pub fn recipe_lines(string:&str) -> Vec<(u32, String)> {
+    let mut recipes = vec![];
+    let mut offset = 0;
+    for line in string.split('\n') {
+        let recipe = line.split('#').next().unwrap();
+        let trimmed = recipe.trim_start();
+        let start = offset + (recipe.chars().count() - trimmed.chars().count()) as u32;
+        if !trimmed.trim().is_empty() {
+            recipes.push((start, trimmed.trim_end().to_string()));
+        }
+        offset += line.chars().count() as u32 + 1;
+    }
+    return recipes;
+}
 
-    fn with_msg(position:u32, msg:&str) -> ParseError {
-        return ParseError{position, msg:msg.to_string()}
+/// names of the substitutes `recipe` refers to that do not exist, found without parsing it
+pub fn unknown_substitutes(recipe:&str) -> Vec<String> {
+    let mut unknown = vec![];
+    for reference in recipe.split('*').skip(1) {
+        let name = reference.split(';').next().unwrap().to_ascii_uppercase();
+        if !SUB_MAP.contains_key(name.as_str()) && !unknown.contains(&name) {
+            unknown.push(name);
+        }
     }
+    return unknown;
 }
 
-pub fn parse(string:String) -> (Result<ChemToken, ParseError>, u32) {
-    let mut tokens = tokenize(string);
+fn parse_tokens(mut tokens:Vec<char>) -> Result<(ChemToken, u32), ParseError> {
     let mut tokens_copy = tokens.clone();
-    let final_quantity;
-    let quantity = parse_number(&mut tokens_copy).unwrap();
-    let maybe_x = peek(&tokens_copy).unwrap();
+    let quantity = parse_number(&mut tokens_copy)?;
+    let maybe_x = peek(&tokens_copy)?;
     if maybe_x == 'x' {
-        assert_token(&mut tokens_copy, 'x').unwrap();
-        final_quantity = match quantity {
+        assert_token(&mut tokens_copy, 'x')?;
+        let final_quantity = match quantity {
             NumberToken::Constant(val) => {val},
-            _ => panic!("Invalid term in constant")
+            _ => return Err(ParseError::NotAConstant{position:tokens_copy.len() as u32 + 1, field:"batch count"})
+        };
+        return Ok((parse_recipe_root(&mut tokens_copy)?, final_quantity));
+    }
+    return Ok((parse_recipe_root(&mut tokens)?, 1));
+}
+
+/// the outermost chemical of a recipe, which has nothing to be a `$` fraction of
+fn parse_recipe_root(tokens:&mut Vec<char>) -> Result<ChemToken, ParseError> {
+    let position = tokens.len() as u32;
+    let chem = parse_group_or_base(tokens, None)?;
+    if !chem.quantity.is_constant() {
+        return Err(ParseError::NotAConstant{position, field:"recipe quantity"});
+    }
+    return Ok(chem);
+}
+
+/// machine contents of format "<slot>=<amount>:<chem>,..", ie "1=100:hydrogen;,4=30:*OIL;"
+pub fn parse_inventory(string:String) -> Result<Vec<(u32, ChemToken)>, ParseError> {
+    let mut inventory = vec![];
+    for entry in string.split(',') {
+        let entry = entry.trim();
+        if entry.is_empty() {
+            continue;
+        }
+        let split:Vec<&str> = entry.splitn(2, '=').collect();
+        if split.len() != 2 {
+            return Err(ParseError::with_msg(0, format!("inventory entry missing '=': {}", entry).as_str()));
+        }
+        let slot = split[0].trim().trim_start_matches('r');
+        let slot:u32 = match slot.parse() {
+            Ok(val) => val,
+            Err(_) => return Err(ParseError::with_msg(0, format!("bad reservoir number: {}", split[0]).as_str()))
+        };
+        let mut tokens = tokenize(split[1].trim().to_string());
+        let mut chem = parse_group_or_base(&mut tokens, None)?;
+        if !chem.quantity.is_constant() {
+            return Err(ParseError::NotAConstant{position:0, field:"inventory amount"});
+        }
+        chem.check_size().map_err(|err| err.at(0))?;
+        chem.set_concrete_quantity(0, Rounding::Up);
+        inventory.push((slot, chem));
+    }
+    return Ok(inventory);
+}
+
+/// reagent pins of format "<name>=<slot>,..", ie "hydrogen=1,nitrogen=2". Newlines separate
+/// entries as well as commas and anything after a '#' is ignored, so the same format works as a file.
+pub fn parse_layout(string:String) -> Result<Vec<(String, u32)>, ParseError> {
+    let mut pins = vec![];
+    for line in string.lines() {
+        let line = line.split('#').next().unwrap();
+        for entry in line.split(',') {
+            let entry = entry.trim();
+            if entry.is_empty() {
+                continue;
+            }
+            let split:Vec<&str> = entry.splitn(2, '=').collect();
+            if split.len() != 2 || split[0].trim().is_empty() {
+                return Err(ParseError::with_msg(0, format!("layout entry should be <reagent>=<reservoir>: {}", entry).as_str()));
+            }
+            let slot = split[1].trim().trim_start_matches('r');
+            let slot:u32 = match slot.parse() {
+                Ok(val) => val,
+                Err(_) => return Err(ParseError::with_msg(0, format!("bad reservoir number: {}", split[1]).as_str()))
+            };
+            pins.push((split[0].trim().to_string(), slot));
         }
-    } else {
-        final_quantity = 1;
     }
-    (if maybe_x == 'x' {parse_group_or_base(&mut tokens_copy, None)} else {parse_group_or_base(&mut tokens, None)}, final_quantity)
+    return Ok(pins);
 }
 
-fn parse_quantity(string:String, quantity:NumberToken) -> Result<ChemToken, ParseError> {
-    let mut tokens = tokenize(string);
-    parse_group_or_base(&mut tokens, Some(quantity))
+/// settings of format "<key>=<value>", one per line, with anything after a '#' ignored
+pub fn parse_settings(string:String) -> Result<Vec<(String, String)>, ParseError> {
+    let mut settings = vec![];
+    for line in string.lines() {
+        let line = line.split('#').next().unwrap().trim();
+        if line.is_empty() {
+            continue;
+        }
+        let split:Vec<&str> = line.splitn(2, '=').collect();
+        if split.len() != 2 || split[0].trim().is_empty() {
+            return Err(ParseError::with_msg(0, format!("setting should be <key>=<value>: {}", line).as_str()));
+        }
+        settings.push((split[0].trim().replace('-', "_"), split[1].trim().to_string()));
+    }
+    return Ok(settings);
 }
 
 fn tokenize(string:String) -> Vec<char> {
     return string.chars().rev().collect()
 }
 
-fn parse_group_or_base(tokens: &mut Vec<char>, last_quantity:Option<NumberToken>) -> Result<ChemToken, ParseError> {
+fn parse_group_or_base(tokens: &mut Vec<char>, _last_quantity:Option<NumberToken>) -> Result<ChemToken, ParseError> {
+    let start = tokens.len() as u32;
     let quantity = parse_number(tokens)?;
     assert_token(tokens, ':')?;
     if tokens.is_empty() {
         return Err(ParseError::with_msg(tokens.len() as u32, "missing (, end of feed"));
     }
     let next = peek(tokens)?;
-    match next {
-        '(' => return parse_group(tokens, quantity),
-        '*' => return parse_subbed_chem(tokens, quantity),
-        _ => return parse_base_chem(tokens, quantity)
+    let mut chem = match next {
+        '(' => parse_group(tokens, quantity)?,
+        '*' => parse_subbed_chem(tokens, quantity)?,
+        _ => parse_base_chem(tokens, quantity)?
+    };
+    let span = Some((start, tokens.len() as u32));
+    if chem.chemical.substitute.is_some() {
+        set_spans(&mut chem, span);
+    } else {
+        chem.source.span = span;
+    }
+    return Ok(chem);
+}
+
+/// set the span of `chem` and everything inside it
+fn set_spans(chem:&mut ChemToken, span:Option<(u32, u32)>) {
+    chem.source.span = span;
+    for inner in &mut chem.chemical.chemicals {
+        set_spans(inner, span);
+    }
+}
+
+/// map both ends of every span inside `chem` through `position`
+fn move_spans(chem:&mut ChemToken, position:&impl Fn(u32) -> u32) {
+    chem.source.span = chem.source.span.map(|(start, end)| (position(start), position(end)));
+    for inner in &mut chem.chemical.chemicals {
+        move_spans(inner, position);
+    }
+}
+
+/// name `substitute` as where `chem` and everything inside came from, unless an inner one is
+fn set_substitute(chem:&mut ChemToken, substitute:&str) {
+    if chem.source.substitute.is_none() {
+        chem.source.substitute = Some(substitute.to_string());
+    }
+    for inner in &mut chem.chemical.chemicals {
+        set_substitute(inner, substitute);
     }
 }
 
@@ -132,74 +279,103 @@ fn assert_token(tokens: &mut Vec<char>, matches:char) -> Result<(), ParseError>
 
 fn parse_subbed_chem(tokens: &mut Vec<char>, quantity:NumberToken) -> Result<ChemToken, ParseError> {
     assert_token(tokens, '*')?;
+    let position = tokens.len() as u32;
     let name = parse_name(tokens)?;
-    let formula = get_substitute_formula(name);
-    let (result, _) = parse(format!("{}:{}", quantity.as_text(), formula));
-    let mut result = result.unwrap();
-    let priority = parse_priority(tokens)?;
-    result.priority = priority;
+    let formula = get_substitute_formula(name.clone(), position)?;
+    // errors inside the formula are reported at the substitution
+    let mut result = parse_group_or_base(&mut tokenize(format!("{}:{}", quantity.as_text(), formula)), None).map_err(|err| err.at(position))?;
+    result.chemical.substitute = Some(name.to_ascii_uppercase());
+    set_substitute(&mut result, &name.to_ascii_uppercase());
+    parse_markers(tokens, &mut result)?;
     return Ok(result);
 }
 
-/// chem group of format "50:(<chem>,..)@<temp>;" where the "@<temp>;" is optional
+/// chem group of format "50:(<chem>,..)@<temp>;" where the "@<temp>;" is optional. Heats may
+/// also go between the chems, ie "50:($/3:a;$/3:b;@374;$/3:c;@420;)", to heat what has been
+/// added so far before going on.
 fn parse_group(tokens: &mut Vec<char>, quantity:NumberToken) -> Result<ChemToken, ParseError> {
     let mut chems = vec![];
+    let mut stages = vec![];
     assert_token(tokens, '(')?;
     while peek(tokens)?!=')' {
-        chems.push(parse_group_or_base(tokens, Some(quantity.clone()))?);
+        if peek(tokens)? == '@' {
+            if chems.is_empty() {
+                return Err(ParseError::with_msg(tokens.len() as u32, "heat before any ingredient in group"));
+            }
+            stages.push((chems.len(), parse_temp(tokens)?));
+        } else {
+            chems.push(parse_group_or_base(tokens, Some(quantity.clone()))?);
+        }
         if tokens.is_empty() {
             return Err(ParseError::with_msg(tokens.len() as u32, "missing ), end of feed"));
         }
     }
+    if chems.is_empty() {
+        return Err(ParseError::with_msg(tokens.len() as u32, "empty group"));
+    }
     assert_token(tokens, ')')?;
-    let temp;
-    if !tokens.is_empty() && peek(tokens)?=='@' {
-        tokens.pop();
-        let temp_token = parse_number(tokens)?;
-        match temp_token {
-            NumberToken::Constant(val) => {
-                temp = Some(val);
-            },
-            _ => panic!("Bad value in temp constant")
-        }
-        let token = tokens.pop();
-        if token.is_none() {
-            return Err(ParseError::with_msg(tokens.len() as u32, "group hit end of feed"));
-        }
-        let token = token.unwrap();
-        if token != ';' {
-            return Err(ParseError::with_msg(tokens.len() as u32, "missing semicolon after temp"));
-        }
+    let temp = if !tokens.is_empty() && peek(tokens)?=='@' {
+        Some(parse_temp(tokens)?)
     } else {
-        temp = None;
-    }
-    let priority = parse_priority(tokens)?;
-    return Ok(ChemToken {quantity:quantity, priority, concrete_quantity:None, chemical: Chemical {chemicals:chems, temp:temp, ..Default::default()}});
+        None
+    };
+    let mut chem = ChemToken {quantity, chemical: Chemical {chemicals:chems, temp, stages, ..Default::default()}, ..Default::default()};
+    parse_markers(tokens, &mut chem)?;
+    return Ok(chem);
 }
 
-fn parse_priority(tokens: &mut Vec<char>) -> Result<u32, ParseError> {
-    if tokens.is_empty() {
-        return Ok(0);
+/// temperature of format "@<temp>;"
+fn parse_temp(tokens: &mut Vec<char>) -> Result<u32, ParseError> {
+    assert_token(tokens, '@')?;
+    let position = tokens.len() as u32;
+    let temp = match parse_number(tokens)? {
+        NumberToken::Constant(val) => val,
+        _ => return Err(ParseError::NotAConstant{position, field:"temperature"})
+    };
+    let token = tokens.pop();
+    if token.is_none() {
+        return Err(ParseError::with_msg(tokens.len() as u32, "group hit end of feed"));
     }
-    let next = peek(tokens)?;
-    if next == '!' {
+    if token.unwrap() != ';' {
+        return Err(ParseError::with_msg(tokens.len() as u32, "missing semicolon after temp"));
+    }
+    return Ok(temp);
+}
+
+/// markers after a chem, any number of "!<priority>;", "!first;", "!last;", "!after:<ingredient>;",
+/// "!build:first;" and "!build:last;"
+fn parse_markers(tokens: &mut Vec<char>, chem:&mut ChemToken) -> Result<(), ParseError> {
+    while !tokens.is_empty() && peek(tokens)? == '!' {
         assert_token(tokens, '!')?;
-        let number = match parse_number(tokens)? {
-            NumberToken::Constant(val) => {
-                val
-            },
-            _ => panic!("Bad constant in priority")
-        };
-        assert_token(tokens, ';')?;
-        return Ok(number);
-    } else {
-        return Ok(0);
+        let position = tokens.len() as u32;
+        if peek(tokens)?.is_ascii_digit() || peek(tokens)? == '$' {
+            chem.priority = match parse_number(tokens)? {
+                NumberToken::Constant(val) => {
+                    val
+                },
+                _ => return Err(ParseError::NotAConstant{position, field:"priority"})
+            };
+            assert_token(tokens, ';')?;
+            continue;
+        }
+        let marker = parse_name(tokens)?;
+        match marker.as_str() {
+            "first" => chem.order.push(AddOrder::First),
+            "last" => chem.order.push(AddOrder::Last),
+            "build:first" => chem.build = Some(BuildOrder::First),
+            "build:last" => chem.build = Some(BuildOrder::Last),
+            _ => match marker.strip_prefix("after:") {
+                Some(name) if !name.is_empty() => chem.order.push(AddOrder::After(name.to_string())),
+                _ => return Err(ParseError::with_msg(position, format!("unknown marker !{};", marker).as_str()))
+            }
+        }
     }
+    return Ok(());
 }
 
-fn peek(tokens: &Vec<char>) -> Result<char, ParseError> {
+fn peek(tokens: &[char]) -> Result<char, ParseError> {
     if tokens.is_empty() {
-        return Err(ParseError::with_msg(tokens.len() as u32, format!("peeked at end of line").as_str()));
+        return Err(ParseError::with_msg(tokens.len() as u32, "peeked at end of line"));
     } else {
         return Ok(*tokens.last().unwrap());
     }
@@ -208,8 +384,9 @@ fn peek(tokens: &Vec<char>) -> Result<char, ParseError> {
 /// basic chem of format "<amount>:<name>;" ie "50:nitrogen;"
 fn parse_base_chem(tokens: &mut Vec<char>, quantity:NumberToken) -> Result<ChemToken, ParseError> {
     let chem_name = parse_name(tokens)?;
-    let priority = parse_priority(tokens)?;
-    return Ok(ChemToken {quantity:quantity, priority, concrete_quantity:None, chemical: Chemical {name:Some(chem_name), ..Default::default()}});
+    let mut chem = ChemToken {quantity, chemical: Chemical {name:Some(chem_name), ..Default::default()}, ..Default::default()};
+    parse_markers(tokens, &mut chem)?;
+    return Ok(chem);
 }
 
 fn parse_name(tokens: &mut Vec<char>) -> Result<String, ParseError> {
@@ -233,7 +410,7 @@ fn parse_name(tokens: &mut Vec<char>) -> Result<String, ParseError> {
 
 fn parse_number(tokens: &mut Vec<char>) -> Result<NumberToken,ParseError> {
     let peek_res = peek(tokens)?;
-    if peek_res.is_digit(10) {
+    if peek_res.is_ascii_digit() {
         let mut digit = parse_digit(tokens);
         if digit.is_none() {
             return Err(ParseError::with_msg(tokens.len() as u32, "number parse error, bad digit"));
@@ -241,22 +418,29 @@ fn parse_number(tokens: &mut Vec<char>) -> Result<NumberToken,ParseError> {
         let mut sum = digit.unwrap();
         digit = parse_digit(tokens);
         while digit.is_some() {
-            sum *= 10;
-            sum += digit.unwrap();
+            sum = match sum.checked_mul(10).and_then(|x| x.checked_add(digit.unwrap())) {
+                Some(sum) => sum,
+                None => return Err(ParseError::with_msg(tokens.len() as u32, "number too large"))
+            };
             digit = parse_digit(tokens);
         }
         return Ok(NumberToken::Constant(sum));
     } else if peek_res == '$' {
         assert_token(tokens, '$')?;
-        let peek_res = peek(tokens).unwrap();
-        let numerator;
-        if peek_res.is_digit(10) {
-            numerator = parse_digit(tokens).unwrap();
+        let peek_res = peek(tokens)?;
+        let numerator = if peek_res.is_ascii_digit() {
+            parse_digit(tokens).unwrap()
         } else {
-            numerator = 1;
-        }
+            1
+        };
         assert_token(tokens, '/')?;
-        let digit = parse_digit(tokens).unwrap();
+        let digit = match parse_digit(tokens) {
+            Some(digit) => digit,
+            None => return Err(ParseError::with_msg(tokens.len() as u32, "number parse error, missing denominator"))
+        };
+        if digit == 0 {
+            return Err(ParseError::with_msg(tokens.len() as u32 + 1, "number parse error, denominator is 0"));
+        }
         return Ok(NumberToken::Calculated(NumberOperator::new(numerator,digit)))
     } else {
         return Err(ParseError::with_msg(tokens.len() as u32, format!("number parse error, NAN: {}", peek_res).as_str()));
@@ -264,12 +448,8 @@ fn parse_number(tokens: &mut Vec<char>) -> Result<NumberToken,ParseError> {
 }
 
 fn parse_digit(tokens: &mut Vec<char>) -> Option<u32> {
-    let token = tokens.pop();
-    if token.is_none() {
-        return None;
-    }
-    let token = token.unwrap();
-    if !token.is_digit(10) {
+    let token = tokens.pop()?;
+    if !token.is_ascii_digit() {
         tokens.push(token);
         return None;
     }
@@ -0,0 +1,233 @@
+use crate::{Error, ProfileError};
+use crate::chemicals::FixedPoint;
+use std::path::PathBuf;
+use structopt::StructOpt;
+
+/// the finest fixed point volumes can be counted in without large beakers overflowing
+pub const MAX_DECIMALS:u32 = 3;
+
+/// The physical machine a program is compiled for. Servers run different builds, so none of
+/// this is fixed: the defaults match the stock ChemiCompiler.
+#[derive(Debug, Clone, Hash, PartialEq, Eq)]
+pub struct MachineProfile {
+    pub reservoirs:u32,
+    /// beaker capacities that can be placed in a reservoir, smallest first
+    pub beakers:Vec<u32>,
+    pub max_pill:u32,
+    pub max_vial:u32,
+    /// transfer targets that are not reservoirs
+    pub pill_target:u32,
+    pub vial_target:u32,
+    pub eject_target:u32,
+    /// amount transferred to empty a reservoir completely, the largest beaker if unset
+    pub transfer_all:Option<u32>,
+    /// temperatures the heater can reach, in kelvin
+    pub min_temp:u32,
+    pub max_temp:u32,
+    /// decimal places of a unit the machine takes volumes in, 0 if only whole units
+    pub decimals:u32,
+    /// the fixed point the volumes above are counted in, see `in_units`
+    pub units:FixedPoint
+}
+
+impl Default for MachineProfile {
+    fn default() -> Self {
+        MachineProfile {
+            reservoirs:10,
+            beakers:vec![50, 100],
+            max_pill:100,
+            max_vial:50,
+            pill_target:11,
+            vial_target:12,
+            eject_target:13,
+            transfer_all:None,
+            min_temp:0,
+            max_temp:1000,
+            decimals:0,
+            units:FixedPoint::default()
+        }
+    }
+}
+
+impl MachineProfile {
+    pub fn largest_beaker(&self) -> u32 {
+        return *self.beakers.last().unwrap_or(&0);
+    }
+
+    pub fn transfer_all(&self) -> u32 {
+        return self.transfer_all.unwrap_or_else(|| self.largest_beaker());
+    }
+
+    /// the fixed point the machine takes volumes in
+    pub fn machine_units(&self) -> FixedPoint {
+        return FixedPoint::new(self.decimals);
+    }
+
+    /// the same machine with its volumes counted in `units` instead
+    pub fn in_units(&self, units:FixedPoint) -> MachineProfile {
+        let convert = |volume:u32| self.units.convert(volume, units);
+        let mut profile = self.clone();
+        profile.beakers = self.beakers.iter().map(|beaker| convert(*beaker)).collect();
+        profile.max_pill = convert(self.max_pill);
+        profile.max_vial = convert(self.max_vial);
+        profile.transfer_all = self.transfer_all.map(convert);
+        profile.units = units;
+        return profile;
+    }
+
+    /// the smallest beaker that holds `size`, if any does
+    pub fn fit(&self, size:u32) -> Option<u32> {
+        if size == 0 {
+            return Some(0);
+        }
+        return self.beakers.iter().copied().find(|beaker| *beaker >= size);
+    }
+
+    /// set a single `key=value` setting, as used by profile files
+    pub fn set(&mut self, key:&str, value:&str) -> Result<(), ProfileError> {
+        let number = |value:&str| -> Result<u32, ProfileError> {
+            match value.trim().parse() {
+                Ok(val) => Ok(val),
+                Err(_) => Err(ProfileError::BadValue{key:key.to_string(), value:value.to_string()})
+            }
+        };
+        match key {
+            "reservoirs" => self.reservoirs = number(value)?,
+            "beakers" => {
+                let mut beakers = vec![];
+                for beaker in value.split(',') {
+                    beakers.push(number(beaker)?);
+                }
+                self.beakers = beakers;
+            },
+            "max_pill" => self.max_pill = number(value)?,
+            "max_vial" => self.max_vial = number(value)?,
+            "pill_target" => self.pill_target = number(value)?,
+            "vial_target" => self.vial_target = number(value)?,
+            "eject_target" => self.eject_target = number(value)?,
+            "transfer_all" => self.transfer_all = Some(number(value)?),
+            "min_temp" => self.min_temp = number(value)?,
+            "max_temp" => self.max_temp = number(value)?,
+            "decimals" => self.decimals = number(value)?,
+            _ => return Err(ProfileError::UnknownKey{key:key.to_string()})
+        }
+        return Ok(());
+    }
+
+    pub fn validate(&mut self) -> Result<(), ProfileError> {
+        if self.reservoirs == 0 {
+            return Err(ProfileError::NoReservoirs);
+        }
+        if self.decimals > MAX_DECIMALS {
+            return Err(ProfileError::TooManyDecimals{decimals:self.decimals, most:MAX_DECIMALS});
+        }
+        self.beakers.retain(|x| *x > 0);
+        self.beakers.sort_unstable();
+        self.beakers.dedup();
+        if self.beakers.is_empty() {
+            return Err(ProfileError::NoBeakers);
+        }
+        let targets = [("pill", self.pill_target), ("vial", self.vial_target), ("eject", self.eject_target)];
+        for i in 0..targets.len() {
+            let (name, target) = targets[i];
+            if target <= self.reservoirs {
+                return Err(ProfileError::TargetIsReservoir{name, target, reservoirs:self.reservoirs});
+            }
+            for (other_name, other_target) in &targets[..i] {
+                if *other_target == target {
+                    return Err(ProfileError::TargetsCollide{first:other_name, second:name, target});
+                }
+            }
+        }
+        return Ok(());
+    }
+}
+
+#[derive(StructOpt, Debug, Default)]
+pub struct ProfileFlags {
+    /// Machine profile file of key=value lines, ie "reservoirs=12" or "beakers=50,100,200".
+    /// Keys are the same as the flags below, with underscores.
+    #[structopt(long, parse(from_os_str))]
+    profile:Option<PathBuf>,
+    /// Number of reservoirs in the machine
+    #[structopt(long)]
+    reservoirs:Option<u32>,
+    /// Beaker capacities available for reservoirs, ie "50,100"
+    #[structopt(long)]
+    beakers:Option<String>,
+    /// Largest pill the machine can press
+    #[structopt(long)]
+    max_pill:Option<u32>,
+    /// Largest vial the machine can fill
+    #[structopt(long)]
+    max_vial:Option<u32>,
+    /// Transfer target that presses a pill
+    #[structopt(long)]
+    pill_target:Option<u32>,
+    /// Transfer target that fills a vial
+    #[structopt(long)]
+    vial_target:Option<u32>,
+    /// Transfer target that ejects the transferred volume
+    #[structopt(long)]
+    eject_target:Option<u32>,
+    /// Amount transferred to empty a reservoir, defaults to the largest beaker
+    #[structopt(long)]
+    transfer_all:Option<u32>,
+    /// Coldest temperature the heater can reach, in kelvin
+    #[structopt(long)]
+    min_temp:Option<u32>,
+    /// Hottest temperature the heater can reach, in kelvin
+    #[structopt(long)]
+    max_temp:Option<u32>,
+    /// Decimal places of a unit the machine takes volumes in, ie 2 if it can transfer 10.25u
+    #[structopt(long)]
+    decimals:Option<u32>
+}
+
+impl ProfileFlags {
+    /// the default profile, overridden by the profile file and then by any flags
+    pub fn load(&self) -> Result<MachineProfile, Error> {
+        let mut profile = MachineProfile::default();
+        if let Some(path) = &self.profile {
+            let text = match std::fs::read_to_string(path) {
+                Ok(text) => text,
+                Err(err) => return Err(Error::Io{path:path.display().to_string(), msg:err.to_string()})
+            };
+            for (key, value) in crate::parser::parse_settings(text)? {
+                profile.set(&key, &value)?;
+            }
+        }
+        if let Some(beakers) = &self.beakers {
+            profile.set("beakers", beakers)?;
+        }
+        profile.reservoirs = self.reservoirs.unwrap_or(profile.reservoirs);
+        profile.max_pill = self.max_pill.unwrap_or(profile.max_pill);
+        profile.max_vial = self.max_vial.unwrap_or(profile.max_vial);
+        profile.pill_target = self.pill_target.unwrap_or(profile.pill_target);
+        profile.vial_target = self.vial_target.unwrap_or(profile.vial_target);
+        profile.eject_target = self.eject_target.unwrap_or(profile.eject_target);
+        profile.transfer_all = self.transfer_all.or(profile.transfer_all);
+        profile.min_temp = self.min_temp.unwrap_or(profile.min_temp);
+        profile.max_temp = self.max_temp.unwrap_or(profile.max_temp);
+        profile.decimals = self.decimals.unwrap_or(profile.decimals);
+        profile.validate()?;
+        return Ok(profile);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flags_override_the_default_machine() {
+        let flags = ProfileFlags {reservoirs:Some(12), beakers:Some("100,0,50,50".to_string()), ..Default::default()};
+        assert_eq!(flags.load(), Err(Error::Profile(ProfileError::TargetIsReservoir{name:"pill", target:11, reservoirs:12})));
+        let flags = ProfileFlags {pill_target:Some(13), vial_target:Some(14), eject_target:Some(15), ..flags};
+        let profile = flags.load().unwrap();
+        assert_eq!((profile.reservoirs, profile.beakers.clone()), (12, vec![50, 100]));
+        assert_eq!((profile.fit(0), profile.fit(60), profile.fit(101)), (Some(0), Some(100), None));
+        assert_eq!(profile.in_units(FixedPoint::new(1)).beakers, vec![500, 1000]);
+        assert_eq!(MachineProfile::default().set("tanks", "4"), Err(ProfileError::UnknownKey{key:"tanks".to_string()}));
+    }
+}